@@ -4,11 +4,18 @@ extern crate erosion;
 
 use std::io::net::ip::{
     Ipv4Addr,
+    Ipv6Addr,
     SocketAddr,
 };
 use std::io::timer::sleep;
+use std::os;
+use std::rand::{
+    task_rng,
+    Rng,
+};
 use std::time::duration::Duration;
 
+use erosion::codec::WireFormat;
 use erosion::membership::Membership;
 use erosion::config;
 
@@ -17,41 +24,66 @@ static EROSION_ADDR: SocketAddr = SocketAddr {
     port: 7201,
 };
 
-fn bind() -> (int, Membership) {
+/// The address of the first member, which can be given as the first
+/// argument, e.g. `[::1]:7201`. Returns `None` if the argument is not an
+/// address.
+fn seed_addr() -> Option<SocketAddr> {
+    let args = os::args();
+    match args.get(1) {
+        Some(arg) => from_str::<SocketAddr>(arg.as_slice()),
+        None => Some(EROSION_ADDR),
+    }
+}
+
+fn bind(seed: SocketAddr) -> (bool, Membership) {
     let mut config = config::local("node1".to_string());
-    let mut index = 1;
-    config.bind_addr = EROSION_ADDR;
+    config.bind_addr = seed;
+    if let Ipv6Addr(..) = seed.ip {
+        // Only MessagePack carries IPv6 addresses
+        config.wire_format = WireFormat::MessagePack;
+    }
 
-    loop {
-        match Membership::bind(config.clone()) {
-            Ok(membership) => return (index, membership),
-            Err(e) => {
-                println!("{}", e);
-
-                index += 1;
-                config.name = format!("node{}", index);
-                config.bind_addr.port += 1;
-            },
-        };
+    match Membership::bind(config.clone()) {
+        Ok(membership) => return (true, membership),
+        Err(e) => println!("{}", e),
+    }
+
+    // The seed address is taken, let the OS pick a port
+    config.name = format!("node-{}", task_rng().gen::<u16>());
+    config.bind_addr.port = 0;
+    match Membership::bind(config) {
+        Ok(membership) => (false, membership),
+        Err(e) => panic!("{}", e),
     }
 }
 
-fn ping(index: int, mut membership: Membership) {
-    match index {
-        1 => {
-            println!("First member, start!");
-            membership.start();
-        },
-        _ => {
-            println!("Join to {}", EROSION_ADDR);
-            membership.join("node1".to_string(), EROSION_ADDR);
-        },
+fn ping(first: bool, mut membership: Membership, seed: SocketAddr) {
+    println!("Bound to {}", membership.local_addr());
+
+    if first {
+        println!("First member, start!");
+        membership.start();
+    } else {
+        println!("Join to {}", seed);
+        if let Err(e) = membership.join("node1".to_string(), seed, false) {
+            println!("{}", e);
+        }
     }
 }
 
 fn main() {
-    let (index, membership) = bind();
-    ping(index, membership);
+    let seed = match seed_addr() {
+        Some(seed) => seed,
+        None => {
+            let args = os::args();
+            println!("Invalid address: {}", args[1]);
+            println!("Usage: {} [SEED_ADDR], e.g. 127.0.0.1:7201 or [::1]:7201", args[0]);
+            os::set_exit_status(2);
+            return;
+        },
+    };
+    let (first, membership) = bind(seed);
+    ping(first, membership, seed);
 
     loop {
         sleep(Duration::seconds(1));
@@ -0,0 +1,276 @@
+use std::cmp::Ordering;
+use std::collections::{
+    BinaryHeap,
+    HashMap,
+};
+use std::comm::TryRecvError;
+use std::io::timer::Timer;
+use std::thread::Thread;
+use std::time::Duration;
+
+use time;
+
+/// Told about the outcome of a ping. Handlers are called on the ack router
+/// thread, so they must not block.
+pub trait AckHandler {
+    /// The ack arrived. `rtt` is the time between registering the handler
+    /// and receiving the ack.
+    fn ack(&mut self, payload: Vec<u8>, rtt: Duration);
+
+    /// An indirect probe helper could not reach the target. The handler
+    /// stays registered.
+    fn nack(&mut self) {}
+
+    /// No ack arrived before the deadline.
+    fn timeout(&mut self);
+
+    /// The remote node refused the request, e.g. a join, for `reason`. By
+    /// default this is treated like a missing ack.
+    fn reject(&mut self, reason: String) {
+        let _ = reason;
+        self.timeout();
+    }
+}
+
+enum Command {
+    Register {
+        seq: u32,
+        sent: u64,
+        deadline: u64,
+        handler: Box<AckHandler + Send>,
+    },
+
+    Ack {
+        seq: u32,
+        received: u64,
+        payload: Vec<u8>,
+    },
+
+    Nack {
+        seq: u32,
+    },
+
+    Reject {
+        seq: u32,
+        reason: String,
+    },
+
+    Cancel {
+        seq: u32,
+    },
+
+    Stop,
+}
+
+struct Waiter {
+    sent: u64,
+    deadline: u64,
+    handler: Box<AckHandler + Send>,
+}
+
+#[deriving(PartialEq, Eq)]
+struct Deadline {
+    at: u64,
+    seq: u32,
+}
+
+// `BinaryHeap` is a max-heap, so reverse the order to pop the earliest
+// deadline first.
+impl PartialOrd for Deadline {
+    fn partial_cmp(&self, other: &Deadline) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Deadline {
+    fn cmp(&self, other: &Deadline) -> Ordering {
+        other.at.cmp(&self.at)
+    }
+}
+
+/// Routes acks and nacks to the handlers waiting for them.
+///
+/// A single thread owns every handler together with a heap of their
+/// deadlines, so waiting for an ack costs neither a timer nor a channel, and
+/// there is no global lock to contend on.
+#[deriving(Clone)]
+pub struct AckRouter {
+    commands: Sender<Command>,
+}
+
+impl AckRouter {
+    pub fn start() -> AckRouter {
+        let (tx, rx) = channel();
+        Thread::spawn(move || {
+            route(rx);
+        }).detach();
+
+        AckRouter {
+            commands: tx,
+        }
+    }
+
+    /// Register `handler` for the ping `seq`. It is removed once the ack
+    /// arrives or `timeout` has passed.
+    pub fn register(&self, seq: u32, timeout: Duration, handler: Box<AckHandler + Send>) {
+        let sent = time::precise_time_ns();
+        let registered = self.commands.send_opt(Command::Register {
+            seq: seq,
+            sent: sent,
+            deadline: sent + timeout.num_nanoseconds().unwrap_or(0) as u64,
+            handler: handler,
+        });
+
+        // The router is stopped, no ack will ever be routed
+        if let Err(Command::Register { mut handler, .. }) = registered {
+            handler.timeout();
+        }
+    }
+
+    pub fn ack(&self, seq: u32, payload: Vec<u8>) {
+        let _ = self.commands.send_opt(Command::Ack {
+            seq: seq,
+            received: time::precise_time_ns(),
+            payload: payload,
+        });
+    }
+
+    pub fn nack(&self, seq: u32) {
+        let _ = self.commands.send_opt(Command::Nack {
+            seq: seq,
+        });
+    }
+
+    pub fn reject(&self, seq: u32, reason: String) {
+        let _ = self.commands.send_opt(Command::Reject {
+            seq: seq,
+            reason: reason,
+        });
+    }
+
+    /// Remove the handler for `seq` without telling it anything, e.g. when
+    /// the ping could not be sent at all.
+    pub fn cancel(&self, seq: u32) {
+        let _ = self.commands.send_opt(Command::Cancel {
+            seq: seq,
+        });
+    }
+
+    /// Stop the router thread. The waiting handlers time out, and so do the
+    /// ones registered later.
+    pub fn stop(&self) {
+        let _ = self.commands.send_opt(Command::Stop);
+    }
+}
+
+fn route(commands: Receiver<Command>) {
+    let mut timer = Timer::new().unwrap();
+    let mut waiters: HashMap<u32, Waiter> = HashMap::new();
+    let mut deadlines = BinaryHeap::new();
+
+    loop {
+        // Handle what is already queued first, so an ack which arrived in
+        // time is never mistaken for a timeout.
+        loop {
+            match commands.try_recv() {
+                Ok(Command::Stop) => return stop(waiters),
+                Ok(command) => handle(command, &mut waiters, &mut deadlines),
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => return,
+            }
+        }
+
+        // Time out the expired handlers
+        let now = time::precise_time_ns();
+        let mut next_deadline = None;
+        while let Some(&Deadline { at, seq }) = deadlines.top() {
+            if at > now {
+                next_deadline = Some(at);
+                break;
+            }
+            deadlines.pop();
+
+            // The handler may already be gone, or registered again
+            let expired = match waiters.get(&seq) {
+                Some(waiter) => waiter.deadline == at,
+                None => false,
+            };
+            if expired {
+                let mut waiter = waiters.remove(&seq).unwrap();
+                waiter.handler.timeout();
+            }
+        }
+
+        let command = match next_deadline {
+            Some(at) => {
+                let timeout = timer.oneshot(Duration::nanoseconds((at - now) as i64));
+                select!(
+                    command = commands.recv_opt() => command,
+                    () = timeout.recv() => continue
+                )
+            },
+            None => commands.recv_opt(),
+        };
+
+        match command {
+            Ok(Command::Stop) => return stop(waiters),
+            Ok(command) => handle(command, &mut waiters, &mut deadlines),
+            // Every router is gone
+            Err(_) => return,
+        }
+    }
+}
+
+fn handle(command: Command, waiters: &mut HashMap<u32, Waiter>,
+          deadlines: &mut BinaryHeap<Deadline>) {
+    match command {
+        Command::Register { seq, sent, deadline, handler } => {
+            deadlines.push(Deadline {
+                at: deadline,
+                seq: seq,
+            });
+            waiters.insert(seq, Waiter {
+                sent: sent,
+                deadline: deadline,
+                handler: handler,
+            });
+        },
+
+        Command::Ack { seq, received, payload } => {
+            if let Some(mut waiter) = waiters.remove(&seq) {
+                // The ack may have been received before the handler was
+                // registered again for the same ping
+                let rtt = if received > waiter.sent {
+                    Duration::nanoseconds((received - waiter.sent) as i64)
+                } else {
+                    Duration::zero()
+                };
+                waiter.handler.ack(payload, rtt);
+            }
+        },
+
+        Command::Nack { seq } => {
+            if let Some(waiter) = waiters.get_mut(&seq) {
+                waiter.handler.nack();
+            }
+        },
+
+        Command::Reject { seq, reason } => {
+            if let Some(mut waiter) = waiters.remove(&seq) {
+                waiter.handler.reject(reason);
+            }
+        },
+
+        Command::Cancel { seq } => {
+            waiters.remove(&seq);
+        },
+
+        Command::Stop => {},
+    }
+}
+
+fn stop(waiters: HashMap<u32, Waiter>) {
+    for (_, mut waiter) in waiters.into_iter() {
+        waiter.handler.timeout();
+    }
+}
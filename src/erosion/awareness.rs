@@ -0,0 +1,54 @@
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// Manages a simple metric for tracking the estimated health of the local
+/// node. Health is primarily the node's ability to respond in the soft
+/// real-time manner required for correct health checking of other nodes in
+/// the cluster.
+pub struct Awareness {
+    /// The upper threshold for the health score (exclusive).
+    max: int,
+
+    /// The current health score. Zero means healthy, higher values mean the
+    /// local node is degraded.
+    score: Mutex<int>,
+}
+
+impl Awareness {
+    pub fn new(max: int) -> Awareness {
+        Awareness {
+            max: max,
+            score: Mutex::new(0),
+        }
+    }
+
+    /// Take the given delta and apply it to the score in a thread-safe
+    /// manner. It also enforces that the score stays within [0, max).
+    pub fn apply_delta(&self, delta: int) {
+        let mut score = self.score.lock();
+        let initial = *score;
+        let mut updated = initial + delta;
+        if updated < 0 {
+            updated = 0;
+        } else if updated > self.max - 1 {
+            updated = self.max - 1;
+        }
+        (*score) = updated;
+
+        if initial != updated {
+            info!("Local health score changed from {} to {}", initial, updated);
+        }
+    }
+
+    /// Returns the raw health score.
+    pub fn health_score(&self) -> int {
+        *self.score.lock()
+    }
+
+    /// Takes the given duration and scales it based on the current score.
+    /// Less healthy nodes will wait longer before declaring other nodes
+    /// failed.
+    pub fn scale_timeout(&self, timeout: Duration) -> Duration {
+        timeout * (self.health_score() + 1) as i32
+    }
+}
@@ -0,0 +1,188 @@
+use std::cmp;
+use std::num::Float;
+
+use message::{
+    COMPOUND_MAX_PARTS,
+    COMPOUND_PART_OVERHEAD,
+};
+
+struct Broadcast {
+    /// Tells the broadcast apart from identical messages, and stays the same
+    /// while the queue is sorted
+    id: u64,
+
+    /// The member the broadcast is about. A newer broadcast about the same
+    /// member invalidates the older one. Events and queries are about no
+    /// member, and never invalidated.
+    name: Option<String>,
+
+    /// The encoded message
+    msg: Vec<u8>,
+
+    /// The number of times the message has been transmitted
+    transmits: uint,
+}
+
+/// Used to queue messages to broadcast to the cluster (via gossip) but limits
+/// the number of transmits per message. It also prioritizes messages with
+/// lower transmit counts (hence newer messages).
+pub struct TransmitLimitedQueue {
+    retransmit_mult: int,
+
+    broadcasts: Vec<Broadcast>,
+
+    /// The id of the next queued broadcast
+    next_id: u64,
+}
+
+impl TransmitLimitedQueue {
+    pub fn new(retransmit_mult: int) -> TransmitLimitedQueue {
+        TransmitLimitedQueue {
+            retransmit_mult: retransmit_mult,
+            broadcasts: Vec::new(),
+            next_id: 0,
+        }
+    }
+
+    /// Queue a message about the member `name`, replacing any pending
+    /// broadcast about the same member.
+    pub fn queue(&mut self, name: String, msg: Vec<u8>) {
+        self.broadcasts.retain(|broadcast| {
+            broadcast.name.as_ref().map_or(true, |other| *other != name)
+        });
+        let id = self.next_id();
+        self.broadcasts.push(Broadcast {
+            id: id,
+            name: Some(name),
+            msg: msg,
+            transmits: 0,
+        });
+    }
+
+    /// Queue a message which is about no member, so it doesn't replace any
+    /// pending broadcast.
+    pub fn queue_unique(&mut self, msg: Vec<u8>) {
+        let id = self.next_id();
+        self.broadcasts.push(Broadcast {
+            id: id,
+            name: None,
+            msg: msg,
+            transmits: 0,
+        });
+    }
+
+    /// Get the messages to piggyback into a packet of at most `limit` bytes,
+    /// where each message costs `COMPOUND_PART_OVERHEAD` extra bytes, along
+    /// with their ids. They are only counted once `transmitted` is called
+    /// with the ids, so messages which failed to be sent are not retired
+    /// early. Messages too large for `limit` on their own can never be sent,
+    /// so they are dropped.
+    pub fn get_broadcasts(&mut self, limit: uint) -> (Vec<u64>, Vec<Vec<u8>>) {
+        self.broadcasts.retain(|broadcast| {
+            let fits = broadcast.msg.len() + COMPOUND_PART_OVERHEAD <= limit;
+            if !fits {
+                warn!("Dropped broadcast of {} bytes, at most {} fit into a packet",
+                      broadcast.msg.len(), limit - cmp::min(limit, COMPOUND_PART_OVERHEAD));
+            }
+            fits
+        });
+
+        // Least transmitted first
+        self.broadcasts.sort_by(|a, b| a.transmits.cmp(&b.transmits));
+
+        let mut used = 0u;
+        let mut ids = Vec::new();
+        let mut msgs = Vec::new();
+        for broadcast in self.broadcasts.iter() {
+            if msgs.len() == COMPOUND_MAX_PARTS {
+                break;
+            }
+
+            let size = broadcast.msg.len() + COMPOUND_PART_OVERHEAD;
+            if used + size > limit {
+                continue;
+            }
+
+            used += size;
+            ids.push(broadcast.id);
+            msgs.push(broadcast.msg.clone());
+        }
+
+        (ids, msgs)
+    }
+
+    /// Count a transmit of each broadcast of `ids`, as returned by
+    /// `get_broadcasts`, and drop the messages which were transmitted often
+    /// enough. Broadcasts replaced in the meantime are not counted. `members`
+    /// is the size of the cluster, used to scale the retransmits.
+    pub fn transmitted(&mut self, ids: &[u64], members: uint) {
+        let transmit_limit = retransmit_limit(self.retransmit_mult, members);
+
+        for broadcast in self.broadcasts.iter_mut() {
+            if ids.contains(&broadcast.id) {
+                broadcast.transmits += 1;
+            }
+        }
+
+        self.broadcasts.retain(|broadcast| broadcast.transmits < transmit_limit);
+    }
+
+    /// Returns the number of messages queued.
+    pub fn len(&self) -> uint {
+        self.broadcasts.len()
+    }
+
+    /// Drop every queued message.
+    pub fn reset(&mut self) {
+        self.broadcasts.clear();
+    }
+
+    fn next_id(&mut self) -> u64 {
+        let id = self.next_id;
+        self.next_id += 1;
+        id
+    }
+}
+
+/// The number of times a message is retransmitted:
+///
+///   retransmits = retransmit_mult * ceil(log10(N+1))
+pub fn retransmit_limit(retransmit_mult: int, members: uint) -> uint {
+    let scale = ((members + 1) as f64).log10().ceil() as uint;
+    retransmit_mult as uint * scale
+}
+
+#[cfg(test)]
+mod tests {
+    use super::TransmitLimitedQueue;
+
+    #[test]
+    fn identical_messages_are_counted_apart() {
+        // One member allows a single transmit
+        let mut queue = TransmitLimitedQueue::new(1);
+        queue.queue_unique(vec![1, 2, 3]);
+        queue.queue_unique(vec![1, 2, 3]);
+
+        let (ids, msgs) = queue.get_broadcasts(8);
+        assert_eq!(msgs, vec![vec![1, 2, 3]]);
+        queue.transmitted(ids.as_slice(), 1);
+        assert_eq!(queue.len(), 1);
+
+        let (ids, _) = queue.get_broadcasts(8);
+        queue.transmitted(ids.as_slice(), 1);
+        assert_eq!(queue.len(), 0);
+    }
+
+    #[test]
+    fn replaced_broadcast_is_not_counted() {
+        let mut queue = TransmitLimitedQueue::new(1);
+        queue.queue("a".to_string(), vec![1]);
+        let (ids, _) = queue.get_broadcasts(64);
+
+        // Sent meanwhile, the newer message about the same member is the
+        // same, but was not transmitted yet
+        queue.queue("a".to_string(), vec![1]);
+        queue.transmitted(ids.as_slice(), 1);
+        assert_eq!(queue.len(), 1);
+    }
+}
@@ -0,0 +1,1362 @@
+use std::cmp;
+use std::collections::BTreeMap;
+use std::io::net::ip::{
+    Ipv4Addr,
+    Ipv6Addr,
+    SocketAddr,
+};
+
+use regex::Regex;
+
+use error::{
+    Error,
+    ErosionResult,
+};
+
+use filter::{
+    Filter,
+    Pattern,
+};
+
+use message::{
+    Message,
+    MessageType,
+    MAX_NAME_LEN,
+    MAX_PAYLOAD_LEN,
+    MAX_QUERY_PAYLOAD_LEN,
+    MAX_REASON_LEN,
+    MAX_TAGS,
+    MAX_TAGS_SIZE,
+    MAX_TAG_LEN,
+};
+
+use msgpack::{
+    Value,
+    read_value,
+    write_value,
+};
+
+/// Turns messages into packets and back.
+pub trait Codec {
+    fn encode(&self, msg: &Message) -> ErosionResult<Vec<u8>>;
+
+    fn decode(&self, buf: &[u8]) -> ErosionResult<Message>;
+}
+
+/// The encoding used for the messages we send. Received messages are
+/// decoded whatever their encoding, see `decode`.
+#[deriving(Copy, Clone, PartialEq, Show)]
+pub enum WireFormat {
+    /// The fixed binary layout of `Message::write`. Understood by every
+    /// version, but any change to a message breaks compatibility, so it
+    /// cannot carry IPv6 addresses.
+    Legacy,
+
+    /// Self-describing MessagePack maps. Unknown fields are ignored, so
+    /// fields can be added without breaking older members.
+    MessagePack,
+}
+
+impl WireFormat {
+    pub fn codec(&self) -> &'static Codec {
+        match *self {
+            WireFormat::Legacy => &LEGACY_CODEC as &'static Codec,
+            WireFormat::MessagePack => &MSGPACK_CODEC as &'static Codec,
+        }
+    }
+
+    /// Returns the codec to encode `msg` with. The query messages have no
+    /// legacy layout, so they are always encoded as MessagePack.
+    pub fn codec_for(&self, msg: &Message) -> &'static Codec {
+        match msg {
+            &Message::Query { .. }
+            | &Message::QueryResponse { .. }
+            | &Message::Relay { .. } => &MSGPACK_CODEC as &'static Codec,
+            _ => self.codec(),
+        }
+    }
+}
+
+static LEGACY_CODEC: LegacyCodec = LegacyCodec;
+static MSGPACK_CODEC: MsgPackCodec = MsgPackCodec;
+
+/// Decode a message in any of the wire formats. Legacy messages start with
+/// their type, which is always below 0x80, whereas MessagePack messages are
+/// maps, which start with a byte of at least 0x80.
+pub fn decode(buf: &[u8]) -> ErosionResult<Message> {
+    if buf.is_empty() {
+        return Err(Error::Truncated);
+    }
+
+    if buf[0] < 0x80 {
+        LEGACY_CODEC.decode(buf)
+    } else {
+        MSGPACK_CODEC.decode(buf)
+    }
+}
+
+pub struct LegacyCodec;
+
+impl Codec for LegacyCodec {
+    fn encode(&self, msg: &Message) -> ErosionResult<Vec<u8>> {
+        let mut buf = Vec::new();
+        if let Err(e) = msg.write(&mut buf) {
+            return Err(e);
+        }
+        Ok(buf)
+    }
+
+    fn decode(&self, buf: &[u8]) -> ErosionResult<Message> {
+        let mut buf = buf;
+        Message::read(&mut buf)
+    }
+}
+
+pub struct MsgPackCodec;
+
+impl Codec for MsgPackCodec {
+    fn encode(&self, msg: &Message) -> ErosionResult<Vec<u8>> {
+        let value = to_value(msg);
+        if let Err(e) = value {
+            return Err(e);
+        }
+
+        let mut buf = Vec::new();
+        if let Err(e) = write_value(&mut buf, &value.unwrap()) {
+            return Err(e);
+        }
+        Ok(buf)
+    }
+
+    fn decode(&self, buf: &[u8]) -> ErosionResult<Message> {
+        let mut buf = buf;
+        let value = read_value(&mut buf);
+        if let Err(e) = value {
+            return Err(e);
+        }
+        from_value(&value.unwrap())
+    }
+}
+
+fn to_value(msg: &Message) -> ErosionResult<Value> {
+    macro_rules! try_addr(
+        ($addr:expr) => (
+            match addr_value($addr) {
+                Ok(value) => value,
+                Err(e) => return Err(e),
+            }
+        )
+    );
+
+    let fields = match msg {
+        &Message::Ping {
+            seq,
+            ref name,
+        } => vec![
+            msg_type(MessageType::Ping),
+            uint_field("seq", seq as u64),
+            str_field("name", name.as_slice()),
+        ],
+
+        &Message::IndirectPing {
+            ref addr,
+            seq,
+            ref name,
+        } => vec![
+            msg_type(MessageType::IndirectPing),
+            field("addr", try_addr!(addr)),
+            uint_field("seq", seq as u64),
+            str_field("name", name.as_slice()),
+        ],
+
+        &Message::Ack {
+            seq,
+            ref payload,
+        } => vec![
+            msg_type(MessageType::Ack),
+            uint_field("seq", seq as u64),
+            field("payload", Value::Bin(payload.clone())),
+        ],
+
+        &Message::Nack {
+            seq,
+        } => vec![
+            msg_type(MessageType::Nack),
+            uint_field("seq", seq as u64),
+        ],
+
+        &Message::Suspect {
+            inc,
+            ref name,
+            ref from,
+        } => vec![
+            msg_type(MessageType::Suspect),
+            uint_field("inc", inc as u64),
+            str_field("name", name.as_slice()),
+            str_field("from", from.as_slice()),
+        ],
+
+        &Message::Alive {
+            inc,
+            ref name,
+            ref addr,
+            ref tags,
+            ltime,
+        } => vec![
+            msg_type(MessageType::Alive),
+            uint_field("inc", inc as u64),
+            str_field("name", name.as_slice()),
+            field("addr", try_addr!(addr)),
+            field("tags", tags_value(tags)),
+            uint_field("ltime", ltime),
+        ],
+
+        &Message::Dead {
+            inc,
+            ref name,
+            ref from,
+            ltime,
+        } => vec![
+            msg_type(MessageType::Dead),
+            uint_field("inc", inc as u64),
+            str_field("name", name.as_slice()),
+            str_field("from", from.as_slice()),
+            uint_field("ltime", ltime),
+        ],
+
+        &Message::Compound {
+            ref msgs,
+        } => {
+            let mut values = Vec::new();
+            for msg in msgs.iter() {
+                match to_value(msg) {
+                    Ok(value) => values.push(value),
+                    Err(e) => return Err(e),
+                }
+            }
+            vec![
+                msg_type(MessageType::Compound),
+                field("msgs", Value::Array(values)),
+            ]
+        },
+
+        &Message::ConflictQuery {
+            seq,
+            ref name,
+        } => vec![
+            msg_type(MessageType::ConflictQuery),
+            uint_field("seq", seq as u64),
+            str_field("name", name.as_slice()),
+        ],
+
+        &Message::Join {
+            seq,
+            inc,
+            ref name,
+            ref addr,
+            ref tags,
+            ltime,
+        } => vec![
+            msg_type(MessageType::Join),
+            uint_field("seq", seq as u64),
+            uint_field("inc", inc as u64),
+            str_field("name", name.as_slice()),
+            field("addr", try_addr!(addr)),
+            field("tags", tags_value(tags)),
+            uint_field("ltime", ltime),
+        ],
+
+        &Message::Reject {
+            seq,
+            ref reason,
+        } => vec![
+            msg_type(MessageType::Reject),
+            uint_field("seq", seq as u64),
+            str_field("reason", reason.as_slice()),
+        ],
+
+        &Message::Query {
+            id,
+            ltime,
+            ref from,
+            ref addr,
+            ref name,
+            ref payload,
+            ref filter,
+            relay_factor,
+        } => vec![
+            msg_type(MessageType::Query),
+            uint_field("id", id as u64),
+            uint_field("ltime", ltime),
+            str_field("from", from.as_slice()),
+            field("addr", try_addr!(addr)),
+            str_field("name", name.as_slice()),
+            field("payload", Value::Bin(payload.clone())),
+            field("filter", filter_value(filter)),
+            uint_field("relay_factor", relay_factor as u64),
+        ],
+
+        &Message::QueryResponse {
+            id,
+            ref from,
+            ack,
+            ref payload,
+        } => vec![
+            msg_type(MessageType::QueryResponse),
+            uint_field("id", id as u64),
+            str_field("from", from.as_slice()),
+            field("ack", Value::Bool(ack)),
+            field("payload", Value::Bin(payload.clone())),
+        ],
+
+        &Message::Relay {
+            ref addr,
+            ref msg,
+        } => vec![
+            msg_type(MessageType::Relay),
+            field("addr", try_addr!(addr)),
+            field("msg", Value::Bin(msg.clone())),
+        ],
+
+        &Message::UserEvent {
+            ltime,
+            ref name,
+            ref payload,
+        } => vec![
+            msg_type(MessageType::UserEvent),
+            uint_field("ltime", ltime),
+            str_field("name", name.as_slice()),
+            field("payload", Value::Bin(payload.clone())),
+        ],
+
+        _ => return Err(Error::UnsupportedMessage),
+    };
+
+    let value = Value::Map(fields);
+    if let Err(e) = check_lengths(&value) {
+        return Err(e);
+    }
+    Ok(value)
+}
+
+fn from_value(value: &Value) -> ErosionResult<Message> {
+    let type_byte = get_uint(value, "type");
+    if let Err(e) = type_byte {
+        return Err(e);
+    }
+    let type_byte = type_byte.unwrap();
+    let message_type: Option<MessageType> = FromPrimitive::from_u64(type_byte);
+    if message_type.is_none() {
+        return Err(Error::UnknownMessageType(type_byte.to_u8().unwrap_or(0xff)));
+    }
+
+    if let Err(e) = check_lengths(value) {
+        return Err(e);
+    }
+
+    match message_type.unwrap() {
+        MessageType::Ping => {
+            let seq = get_u32(value, "seq");
+            if let Err(e) = seq {
+                return Err(e);
+            }
+
+            let name = get_str(value, "name");
+            if let Err(e) = name {
+                return Err(e);
+            }
+
+            Ok(Message::Ping {
+                seq: seq.unwrap(),
+                name: name.unwrap(),
+            })
+        },
+
+        MessageType::IndirectPing => {
+            let addr = get_addr(value, "addr");
+            if let Err(e) = addr {
+                return Err(e);
+            }
+
+            let seq = get_u32(value, "seq");
+            if let Err(e) = seq {
+                return Err(e);
+            }
+
+            let name = get_str(value, "name");
+            if let Err(e) = name {
+                return Err(e);
+            }
+
+            Ok(Message::IndirectPing {
+                addr: addr.unwrap(),
+                seq: seq.unwrap(),
+                name: name.unwrap(),
+            })
+        },
+
+        MessageType::Ack => {
+            let seq = get_u32(value, "seq");
+            if let Err(e) = seq {
+                return Err(e);
+            }
+
+            // The payload is optional
+            let payload = match value.get("payload") {
+                Some(payload) => match payload.as_bin() {
+                    Some(payload) => payload.to_vec(),
+                    None => return Err(Error::InvalidField("payload")),
+                },
+                None => Vec::new(),
+            };
+
+            Ok(Message::Ack {
+                seq: seq.unwrap(),
+                payload: payload,
+            })
+        },
+
+        MessageType::Nack => {
+            let seq = get_u32(value, "seq");
+            if let Err(e) = seq {
+                return Err(e);
+            }
+
+            Ok(Message::Nack {
+                seq: seq.unwrap(),
+            })
+        },
+
+        MessageType::Suspect => {
+            let inc = get_u32(value, "inc");
+            if let Err(e) = inc {
+                return Err(e);
+            }
+
+            let name = get_str(value, "name");
+            if let Err(e) = name {
+                return Err(e);
+            }
+
+            let from = get_str(value, "from");
+            if let Err(e) = from {
+                return Err(e);
+            }
+
+            Ok(Message::Suspect {
+                inc: inc.unwrap(),
+                name: name.unwrap(),
+                from: from.unwrap(),
+            })
+        },
+
+        MessageType::Alive => {
+            let inc = get_u32(value, "inc");
+            if let Err(e) = inc {
+                return Err(e);
+            }
+
+            let name = get_str(value, "name");
+            if let Err(e) = name {
+                return Err(e);
+            }
+
+            let addr = get_addr(value, "addr");
+            if let Err(e) = addr {
+                return Err(e);
+            }
+
+            let tags = get_tags(value, "tags");
+            if let Err(e) = tags {
+                return Err(e);
+            }
+
+            let ltime = get_ltime(value, "ltime");
+            if let Err(e) = ltime {
+                return Err(e);
+            }
+
+            Ok(Message::Alive {
+                inc: inc.unwrap(),
+                name: name.unwrap(),
+                addr: addr.unwrap(),
+                tags: tags.unwrap(),
+                ltime: ltime.unwrap(),
+            })
+        },
+
+        MessageType::Dead => {
+            let inc = get_u32(value, "inc");
+            if let Err(e) = inc {
+                return Err(e);
+            }
+
+            let name = get_str(value, "name");
+            if let Err(e) = name {
+                return Err(e);
+            }
+
+            let from = get_str(value, "from");
+            if let Err(e) = from {
+                return Err(e);
+            }
+
+            let ltime = get_ltime(value, "ltime");
+            if let Err(e) = ltime {
+                return Err(e);
+            }
+
+            Ok(Message::Dead {
+                inc: inc.unwrap(),
+                name: name.unwrap(),
+                from: from.unwrap(),
+                ltime: ltime.unwrap(),
+            })
+        },
+
+        MessageType::Compound => {
+            let values = value.get("msgs").and_then(|msgs| msgs.as_array());
+            if values.is_none() {
+                return Err(Error::InvalidField("msgs"));
+            }
+
+            let mut msgs = Vec::new();
+            for value in values.unwrap().iter() {
+                // A compound part may not be a compound itself, as in the
+                // legacy format
+                let part_type = value.get("type").and_then(|t| t.as_u64());
+                if part_type == Some(MessageType::Compound as u64) {
+                    return Err(Error::InvalidField("compound message part"));
+                }
+                match from_value(value) {
+                    Ok(msg) => msgs.push(msg),
+                    Err(e) => return Err(e),
+                }
+            }
+
+            Ok(Message::Compound {
+                msgs: msgs,
+            })
+        },
+
+        MessageType::ConflictQuery => {
+            let seq = get_u32(value, "seq");
+            if let Err(e) = seq {
+                return Err(e);
+            }
+
+            let name = get_str(value, "name");
+            if let Err(e) = name {
+                return Err(e);
+            }
+
+            Ok(Message::ConflictQuery {
+                seq: seq.unwrap(),
+                name: name.unwrap(),
+            })
+        },
+
+        MessageType::Join => {
+            let seq = get_u32(value, "seq");
+            if let Err(e) = seq {
+                return Err(e);
+            }
+
+            let inc = get_u32(value, "inc");
+            if let Err(e) = inc {
+                return Err(e);
+            }
+
+            let name = get_str(value, "name");
+            if let Err(e) = name {
+                return Err(e);
+            }
+
+            let addr = get_addr(value, "addr");
+            if let Err(e) = addr {
+                return Err(e);
+            }
+
+            let tags = get_tags(value, "tags");
+            if let Err(e) = tags {
+                return Err(e);
+            }
+
+            let ltime = get_ltime(value, "ltime");
+            if let Err(e) = ltime {
+                return Err(e);
+            }
+
+            Ok(Message::Join {
+                seq: seq.unwrap(),
+                inc: inc.unwrap(),
+                name: name.unwrap(),
+                addr: addr.unwrap(),
+                tags: tags.unwrap(),
+                ltime: ltime.unwrap(),
+            })
+        },
+
+        MessageType::Reject => {
+            let seq = get_u32(value, "seq");
+            if let Err(e) = seq {
+                return Err(e);
+            }
+
+            let reason = get_str(value, "reason");
+            if let Err(e) = reason {
+                return Err(e);
+            }
+
+            Ok(Message::Reject {
+                seq: seq.unwrap(),
+                reason: reason.unwrap(),
+            })
+        },
+
+        MessageType::Query => {
+            let id = get_u32(value, "id");
+            if let Err(e) = id {
+                return Err(e);
+            }
+
+            let ltime = get_uint(value, "ltime");
+            if let Err(e) = ltime {
+                return Err(e);
+            }
+
+            let from = get_str(value, "from");
+            if let Err(e) = from {
+                return Err(e);
+            }
+
+            let addr = get_addr(value, "addr");
+            if let Err(e) = addr {
+                return Err(e);
+            }
+
+            let name = get_str(value, "name");
+            if let Err(e) = name {
+                return Err(e);
+            }
+
+            let payload = get_bin(value, "payload");
+            if let Err(e) = payload {
+                return Err(e);
+            }
+
+            let filter = get_filter(value, "filter");
+            if let Err(e) = filter {
+                return Err(e);
+            }
+
+            let relay_factor = get_uint(value, "relay_factor");
+            if let Err(e) = relay_factor {
+                return Err(e);
+            }
+            let relay_factor = relay_factor.unwrap().to_u8();
+            if relay_factor.is_none() {
+                return Err(Error::InvalidField("relay_factor"));
+            }
+
+            Ok(Message::Query {
+                id: id.unwrap(),
+                ltime: ltime.unwrap(),
+                from: from.unwrap(),
+                addr: addr.unwrap(),
+                name: name.unwrap(),
+                payload: payload.unwrap(),
+                filter: filter.unwrap(),
+                relay_factor: relay_factor.unwrap(),
+            })
+        },
+
+        MessageType::QueryResponse => {
+            let id = get_u32(value, "id");
+            if let Err(e) = id {
+                return Err(e);
+            }
+
+            let from = get_str(value, "from");
+            if let Err(e) = from {
+                return Err(e);
+            }
+
+            let ack = match value.get("ack") {
+                Some(&Value::Bool(ack)) => ack,
+                _ => return Err(Error::InvalidField("ack")),
+            };
+
+            let payload = get_bin(value, "payload");
+            if let Err(e) = payload {
+                return Err(e);
+            }
+
+            Ok(Message::QueryResponse {
+                id: id.unwrap(),
+                from: from.unwrap(),
+                ack: ack,
+                payload: payload.unwrap(),
+            })
+        },
+
+        MessageType::Relay => {
+            let addr = get_addr(value, "addr");
+            if let Err(e) = addr {
+                return Err(e);
+            }
+
+            let msg = get_bin(value, "msg");
+            if let Err(e) = msg {
+                return Err(e);
+            }
+
+            Ok(Message::Relay {
+                addr: addr.unwrap(),
+                msg: msg.unwrap(),
+            })
+        },
+
+        MessageType::UserEvent => {
+            let ltime = get_uint(value, "ltime");
+            if let Err(e) = ltime {
+                return Err(e);
+            }
+
+            let name = get_str(value, "name");
+            if let Err(e) = name {
+                return Err(e);
+            }
+
+            let payload = get_bin(value, "payload");
+            if let Err(e) = payload {
+                return Err(e);
+            }
+
+            Ok(Message::UserEvent {
+                ltime: ltime.unwrap(),
+                name: name.unwrap(),
+                payload: payload.unwrap(),
+            })
+        },
+    }
+}
+
+/// Apply the same limits as the legacy layout to names, tags and payloads.
+fn check_lengths(value: &Value) -> ErosionResult<()> {
+    let fields = [("name", "Name", MAX_NAME_LEN),
+                  ("from", "Name", MAX_NAME_LEN),
+                  ("reason", "Reason", MAX_REASON_LEN)];
+    for &(key, field, max) in fields.iter() {
+        if let Some(s) = value.get(key).and_then(|s| s.as_str()) {
+            if s.len() > max {
+                return Err(Error::TooLong {
+                    field: field,
+                    len: s.len(),
+                    max: max,
+                });
+            }
+        }
+    }
+
+    if let Err(e) = get_tags(value, "tags") {
+        return Err(e);
+    }
+
+    // Queries and their responses may carry larger payloads than acks
+    let max_payload = match get_uint(value, "type") {
+        Ok(t) if t == MessageType::Query as u64 || t == MessageType::QueryResponse as u64 => {
+            MAX_QUERY_PAYLOAD_LEN
+        },
+        _ => MAX_PAYLOAD_LEN,
+    };
+    if let Some(payload) = value.get("payload").and_then(|p| p.as_bin()) {
+        if payload.len() > max_payload {
+            return Err(Error::TooLong {
+                field: "Payload",
+                len: payload.len(),
+                max: max_payload,
+            });
+        }
+    }
+
+    Ok(())
+}
+
+fn field(key: &str, value: Value) -> (Value, Value) {
+    (Value::Str(key.to_string()), value)
+}
+
+fn msg_type(message_type: MessageType) -> (Value, Value) {
+    field("type", Value::UInt(message_type as u64))
+}
+
+fn uint_field(key: &str, value: u64) -> (Value, Value) {
+    field(key, Value::UInt(value))
+}
+
+fn str_field(key: &str, value: &str) -> (Value, Value) {
+    field(key, Value::Str(value.to_string()))
+}
+
+/// The IP is 4 bytes for IPv4 and 16 bytes for IPv6, so its length tells
+/// the address family.
+fn addr_value(addr: &SocketAddr) -> ErosionResult<Value> {
+    let ip = match addr.ip {
+        Ipv4Addr(a, b, c, d) => vec![a, b, c, d],
+        Ipv6Addr(a, b, c, d, e, f, g, h) => {
+            let mut ip = Vec::with_capacity(16);
+            for segment in [a, b, c, d, e, f, g, h].iter() {
+                ip.push((*segment >> 8) as u8);
+                ip.push(*segment as u8);
+            }
+            ip
+        },
+    };
+
+    Ok(Value::Map(vec![
+        field("ip", Value::Bin(ip)),
+        uint_field("port", addr.port as u64),
+    ]))
+}
+
+fn tags_value(tags: &BTreeMap<String, String>) -> Value {
+    Value::Map(tags.iter().map(|(key, value)| {
+        (Value::Str(key.clone()), Value::Str(value.clone()))
+    }).collect())
+}
+
+/// Tags are optional, and missing for nodes which predate them.
+fn get_tags(value: &Value, key: &'static str) -> ErosionResult<BTreeMap<String, String>> {
+    let mut tags = BTreeMap::new();
+    let entries = match value.get(key) {
+        Some(&Value::Map(ref entries)) => entries,
+        Some(_) => return Err(Error::InvalidField(key)),
+        None => return Ok(tags),
+    };
+
+    if entries.len() > MAX_TAGS {
+        return Err(Error::TooLong {
+            field: "Tags",
+            len: entries.len(),
+            max: MAX_TAGS,
+        });
+    }
+
+    let mut size = 0u;
+    for &(ref k, ref v) in entries.iter() {
+        match (k.as_str(), v.as_str()) {
+            (Some(k), Some(v)) => {
+                if k.len() > MAX_TAG_LEN || v.len() > MAX_TAG_LEN {
+                    return Err(Error::TooLong {
+                        field: "Tag",
+                        len: cmp::max(k.len(), v.len()),
+                        max: MAX_TAG_LEN,
+                    });
+                }
+                size += k.len() + v.len();
+                tags.insert(k.to_string(), v.to_string());
+            },
+            _ => return Err(Error::InvalidField(key)),
+        }
+    }
+
+    if size > MAX_TAGS_SIZE {
+        return Err(Error::TooLong {
+            field: "Tags",
+            len: size,
+            max: MAX_TAGS_SIZE,
+        });
+    }
+    Ok(tags)
+}
+
+/// A filter is a map which may hold the pattern of the name, and a map
+/// from tag keys to the patterns of their values.
+fn filter_value(filter: &Filter) -> Value {
+    let mut fields = Vec::new();
+    if let Some(ref name) = filter.name {
+        fields.push(field("name", pattern_value(name)));
+    }
+    if !filter.tags.is_empty() {
+        fields.push(field("tags", Value::Map(filter.tags.iter().map(|&(ref key, ref pattern)| {
+            (Value::Str(key.clone()), pattern_value(pattern))
+        }).collect())));
+    }
+    Value::Map(fields)
+}
+
+/// A pattern is a map with a single entry, its kind and its string.
+fn pattern_value(pattern: &Pattern) -> Value {
+    let (kind, s) = match pattern {
+        &Pattern::Exact(ref exact) => ("exact", exact.as_slice()),
+        &Pattern::Prefix(ref prefix) => ("prefix", prefix.as_slice()),
+        &Pattern::Regex(ref regex) => ("regex", regex.as_str()),
+    };
+    Value::Map(vec![str_field(kind, s)])
+}
+
+fn get_filter(value: &Value, key: &'static str) -> ErosionResult<Filter> {
+    let filter = value.get(key);
+    match filter {
+        Some(&Value::Map(..)) => {},
+        _ => return Err(Error::InvalidField(key)),
+    }
+    let filter = filter.unwrap();
+
+    let name = match filter.get("name") {
+        Some(name) => match get_pattern(name) {
+            Ok(name) => Some(name),
+            Err(e) => return Err(e),
+        },
+        None => None,
+    };
+
+    let mut tags = Vec::new();
+    match filter.get("tags") {
+        Some(&Value::Map(ref entries)) => {
+            if entries.len() > MAX_TAGS {
+                return Err(Error::TooLong {
+                    field: "Tags",
+                    len: entries.len(),
+                    max: MAX_TAGS,
+                });
+            }
+            for &(ref k, ref v) in entries.iter() {
+                let k = match k.as_str() {
+                    Some(k) => k.to_string(),
+                    None => return Err(Error::InvalidField(key)),
+                };
+                match get_pattern(v) {
+                    Ok(pattern) => tags.push((k, pattern)),
+                    Err(e) => return Err(e),
+                }
+            }
+        },
+        Some(_) => return Err(Error::InvalidField(key)),
+        None => {},
+    }
+
+    Ok(Filter {
+        name: name,
+        tags: tags,
+    })
+}
+
+fn get_pattern(value: &Value) -> ErosionResult<Pattern> {
+    let (kind, s) = match value {
+        &Value::Map(ref entries) if entries.len() == 1 => {
+            let (ref kind, ref s) = entries[0];
+            match (kind.as_str(), s.as_str()) {
+                (Some(kind), Some(s)) if s.len() <= MAX_TAG_LEN => (kind, s),
+                _ => return Err(Error::InvalidField("pattern")),
+            }
+        },
+        _ => return Err(Error::InvalidField("pattern")),
+    };
+
+    match kind {
+        "exact" => Ok(Pattern::Exact(s.to_string())),
+        "prefix" => Ok(Pattern::Prefix(s.to_string())),
+        "regex" => match Regex::new(s) {
+            Ok(regex) => Ok(Pattern::Regex(regex)),
+            Err(_) => Err(Error::InvalidField("pattern")),
+        },
+        _ => Err(Error::InvalidField("pattern")),
+    }
+}
+
+fn get_uint(value: &Value, key: &'static str) -> ErosionResult<u64> {
+    match value.get(key).and_then(|v| v.as_u64()) {
+        Some(v) => Ok(v),
+        None => Err(Error::InvalidField(key)),
+    }
+}
+
+/// Lamport times are missing for nodes which predate them, which are at
+/// time 0.
+fn get_ltime(value: &Value, key: &'static str) -> ErosionResult<u64> {
+    match value.get(key) {
+        Some(ltime) => match ltime.as_u64() {
+            Some(ltime) => Ok(ltime),
+            None => Err(Error::InvalidField(key)),
+        },
+        None => Ok(0),
+    }
+}
+
+fn get_u32(value: &Value, key: &'static str) -> ErosionResult<u32> {
+    match get_uint(value, key) {
+        Ok(v) => match v.to_u32() {
+            Some(v) => Ok(v),
+            None => Err(Error::InvalidField(key)),
+        },
+        Err(e) => Err(e),
+    }
+}
+
+fn get_str(value: &Value, key: &'static str) -> ErosionResult<String> {
+    match value.get(key).and_then(|v| v.as_str()) {
+        Some(v) => Ok(v.to_string()),
+        None => Err(Error::InvalidField(key)),
+    }
+}
+
+fn get_bin(value: &Value, key: &'static str) -> ErosionResult<Vec<u8>> {
+    match value.get(key).and_then(|v| v.as_bin()) {
+        Some(v) => Ok(v.to_vec()),
+        None => Err(Error::InvalidField(key)),
+    }
+}
+
+fn get_addr(value: &Value, key: &'static str) -> ErosionResult<SocketAddr> {
+    let addr = value.get(key);
+    if addr.is_none() {
+        return Err(Error::InvalidField(key));
+    }
+    let addr = addr.unwrap();
+
+    let port = get_uint(addr, "port");
+    if let Err(e) = port {
+        return Err(e);
+    }
+    let port = port.unwrap().to_u16();
+    if port.is_none() {
+        return Err(Error::InvalidField("port"));
+    }
+
+    match addr.get("ip").and_then(|ip| ip.as_bin()) {
+        Some(ip) if ip.len() == 4 => Ok(SocketAddr {
+            ip: Ipv4Addr(ip[0], ip[1], ip[2], ip[3]),
+            port: port.unwrap(),
+        }),
+        Some(ip) if ip.len() == 16 => {
+            let segment = |i: uint| (ip[2 * i] as u16 << 8) | ip[2 * i + 1] as u16;
+            Ok(SocketAddr {
+                ip: Ipv6Addr(segment(0), segment(1), segment(2), segment(3),
+                             segment(4), segment(5), segment(6), segment(7)),
+                port: port.unwrap(),
+            })
+        },
+        _ => Err(Error::InvalidField("address")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+    use std::io::net::ip::{
+        Ipv4Addr,
+        Ipv6Addr,
+        SocketAddr,
+    };
+    use std::rand::{
+        Rng,
+        SeedableRng,
+        XorShiftRng,
+    };
+
+    use regex::Regex;
+
+    use error::Error;
+
+    use filter::{
+        Filter,
+        Pattern,
+    };
+
+    use message::{
+        Message,
+        MAX_TAGS,
+        MAX_TAGS_SIZE,
+        MAX_TAG_LEN,
+        write_compound,
+    };
+
+    use msgpack::{
+        Value,
+        write_value,
+    };
+
+    use super::{
+        WireFormat,
+        decode,
+    };
+
+    fn rng() -> XorShiftRng {
+        SeedableRng::from_seed([11, 22, 33, 44])
+    }
+
+    fn random_str(rng: &mut XorShiftRng) -> String {
+        let len = rng.gen_range(0u, 16);
+        rng.gen_ascii_chars().take(len).collect()
+    }
+
+    fn random_bytes(rng: &mut XorShiftRng) -> Vec<u8> {
+        let len = rng.gen_range(0u, 64);
+        rng.gen_iter::<u8>().take(len).collect()
+    }
+
+    /// The legacy layout only carries IPv4 addresses.
+    fn random_addr(rng: &mut XorShiftRng, ipv6: bool) -> SocketAddr {
+        let ip = if ipv6 {
+            Ipv6Addr(rng.gen(), rng.gen(), rng.gen(), rng.gen(),
+                     rng.gen(), rng.gen(), rng.gen(), rng.gen())
+        } else {
+            Ipv4Addr(rng.gen(), rng.gen(), rng.gen(), rng.gen())
+        };
+        SocketAddr {
+            ip: ip,
+            port: rng.gen(),
+        }
+    }
+
+    fn random_tags(rng: &mut XorShiftRng) -> BTreeMap<String, String> {
+        let mut tags = BTreeMap::new();
+        for _ in range(0, rng.gen_range(0u, 4)) {
+            tags.insert(random_str(rng), random_str(rng));
+        }
+        tags
+    }
+
+    fn random_pattern(rng: &mut XorShiftRng) -> Pattern {
+        match rng.gen_range(0u, 3) {
+            0 => Pattern::Exact(random_str(rng)),
+            1 => Pattern::Prefix(random_str(rng)),
+            _ => Pattern::Regex(Regex::new("^web-[0-9]+$").unwrap()),
+        }
+    }
+
+    fn random_filter(rng: &mut XorShiftRng) -> Filter {
+        let mut filter = Filter::new();
+        if rng.gen() {
+            filter.name = Some(random_pattern(rng));
+        }
+        for _ in range(0, rng.gen_range(0u, 3)) {
+            let key = random_str(rng);
+            filter.tags.push((key, random_pattern(rng)));
+        }
+        filter
+    }
+
+    /// A random message of any kind but compound.
+    fn random_message(rng: &mut XorShiftRng, ipv6: bool) -> Message {
+        match rng.gen_range(0u, 14) {
+            0 => Message::Ping { seq: rng.gen(), name: random_str(rng) },
+            1 => Message::IndirectPing { addr: random_addr(rng, ipv6), seq: rng.gen(),
+                                         name: random_str(rng) },
+            2 => Message::Ack { seq: rng.gen(), payload: random_bytes(rng) },
+            3 => Message::Nack { seq: rng.gen() },
+            4 => Message::Suspect { inc: rng.gen(), name: random_str(rng),
+                                    from: random_str(rng) },
+            5 => Message::Alive { inc: rng.gen(), name: random_str(rng),
+                                  addr: random_addr(rng, ipv6), tags: random_tags(rng),
+                                  ltime: rng.gen() },
+            6 => Message::Dead { inc: rng.gen(), name: random_str(rng), from: random_str(rng),
+                                 ltime: rng.gen() },
+            7 => Message::ConflictQuery { seq: rng.gen(), name: random_str(rng) },
+            8 => Message::Join { seq: rng.gen(), inc: rng.gen(), name: random_str(rng),
+                                 addr: random_addr(rng, ipv6), tags: random_tags(rng),
+                                 ltime: rng.gen() },
+            9 => Message::Reject { seq: rng.gen(), reason: random_str(rng) },
+            10 => Message::Query { id: rng.gen(), ltime: rng.gen(), from: random_str(rng),
+                                   addr: random_addr(rng, ipv6), name: random_str(rng),
+                                   payload: random_bytes(rng), filter: random_filter(rng),
+                                   relay_factor: rng.gen() },
+            11 => Message::QueryResponse { id: rng.gen(), from: random_str(rng),
+                                           ack: rng.gen(), payload: random_bytes(rng) },
+            12 => Message::Relay { addr: random_addr(rng, ipv6), msg: random_bytes(rng) },
+            _ => Message::UserEvent { ltime: rng.gen(), name: random_str(rng),
+                                      payload: random_bytes(rng) },
+        }
+    }
+
+    /// The query messages are always encoded as MessagePack, so they can't
+    /// be parts of a legacy compound message.
+    fn has_legacy_layout(msg: &Message) -> bool {
+        match msg {
+            &Message::Query { .. }
+            | &Message::QueryResponse { .. }
+            | &Message::Relay { .. } => false,
+            _ => true,
+        }
+    }
+
+    fn assert_round_trip(format: WireFormat, msg: &Message) {
+        let buf = format.codec_for(msg).encode(msg).unwrap();
+        let decoded = decode(buf.as_slice());
+        assert!(decoded.is_ok(), "{} failed to decode as {}: {}", msg, format, decoded);
+        assert_eq!(format!("{}", decoded.unwrap()), format!("{}", msg));
+    }
+
+    #[test]
+    fn random_messages_round_trip_in_both_formats() {
+        let mut rng = rng();
+        for _ in range(0u, 2000) {
+            assert_round_trip(WireFormat::Legacy, &random_message(&mut rng, false));
+            let ipv6 = rng.gen();
+            assert_round_trip(WireFormat::MessagePack, &random_message(&mut rng, ipv6));
+        }
+    }
+
+    #[test]
+    fn random_compounds_round_trip_in_both_formats() {
+        let mut rng = rng();
+        for _ in range(0u, 200) {
+            for &format in [WireFormat::Legacy, WireFormat::MessagePack].iter() {
+                let mut parts = Vec::new();
+                for _ in range(0, rng.gen_range(0u, 8)) {
+                    let part = random_message(&mut rng, false);
+                    if format == WireFormat::MessagePack || has_legacy_layout(&part) {
+                        parts.push(part);
+                    }
+                }
+                assert_round_trip(format, &Message::Compound { msgs: parts });
+            }
+        }
+    }
+
+    #[test]
+    fn truncated_msgpack_messages_fail() {
+        let mut rng = rng();
+        for _ in range(0u, 200) {
+            let msg = random_message(&mut rng, false);
+            let buf = WireFormat::MessagePack.codec_for(&msg).encode(&msg).unwrap();
+            for len in range(0, buf.len()) {
+                let result = decode(buf[..len]);
+                assert!(result.is_err(), "{} decoded from {} bytes", msg, len);
+            }
+        }
+    }
+
+    #[test]
+    fn truncated_legacy_messages_never_panic() {
+        let mut rng = rng();
+        for _ in range(0u, 200) {
+            let msg = random_message(&mut rng, false);
+            let buf = WireFormat::Legacy.codec_for(&msg).encode(&msg).unwrap();
+            for len in range(0, buf.len()) {
+                let _ = decode(buf[..len]);
+            }
+        }
+    }
+
+    #[test]
+    fn empty_buffer_fails() {
+        assert!(decode(&[]).is_err());
+    }
+
+    #[test]
+    fn msgpack_without_type_fails() {
+        let mut buf = Vec::new();
+        write_value(&mut buf, &Value::Map(vec![
+            (Value::Str("seq".to_string()), Value::UInt(1)),
+        ])).unwrap();
+        assert!(decode(buf.as_slice()).is_err());
+    }
+
+    #[test]
+    fn msgpack_with_too_many_tags_fails() {
+        let tags = range(0, MAX_TAGS + 1).map(|i| {
+            (Value::Str(i.to_string()), Value::Str(String::new()))
+        }).collect();
+        let mut buf = Vec::new();
+        write_value(&mut buf, &Value::Map(vec![
+            (Value::Str("type".to_string()), Value::UInt(5)),
+            (Value::Str("tags".to_string()), Value::Map(tags)),
+        ])).unwrap();
+        assert!(decode(buf.as_slice()).is_err());
+    }
+
+    #[test]
+    fn msgpack_with_overlong_tag_fails() {
+        let long = String::from_char(MAX_TAG_LEN + 1, 'x');
+        let mut buf = Vec::new();
+        write_value(&mut buf, &Value::Map(vec![
+            (Value::Str("type".to_string()), Value::UInt(5)),
+            (Value::Str("tags".to_string()), Value::Map(vec![
+                (Value::Str("role".to_string()), Value::Str(long)),
+            ])),
+        ])).unwrap();
+        assert!(decode(buf.as_slice()).is_err());
+    }
+
+    #[test]
+    fn msgpack_with_oversized_tags_fails() {
+        // Every tag is short enough, but not all of them together
+        let tags = range(0, 2u).map(|i| {
+            (Value::Str(i.to_string()), Value::Str(String::from_char(MAX_TAGS_SIZE / 2, 'x')))
+        }).collect();
+        let mut buf = Vec::new();
+        write_value(&mut buf, &Value::Map(vec![
+            (Value::Str("type".to_string()), Value::UInt(5)),
+            (Value::Str("tags".to_string()), Value::Map(tags)),
+        ])).unwrap();
+        assert!(decode(buf.as_slice()).is_err());
+    }
+
+    #[test]
+    fn deeply_nested_msgpack_fails() {
+        // A fixarray holding a fixarray, and so on
+        let buf = Vec::from_elem(1000, 0x91u8);
+        assert!(decode(buf.as_slice()).is_err());
+    }
+
+    #[test]
+    fn nested_msgpack_compound_fails() {
+        fn compound(msgs: Vec<Value>) -> Value {
+            Value::Map(vec![
+                (Value::Str("type".to_string()), Value::UInt(7)),
+                (Value::Str("msgs".to_string()), Value::Array(msgs)),
+            ])
+        }
+
+        let mut buf = Vec::new();
+        write_value(&mut buf, &compound(vec![compound(Vec::new())])).unwrap();
+        match decode(buf.as_slice()) {
+            Err(Error::InvalidField(..)) => {},
+            result => panic!("Unexpected {}", result),
+        }
+    }
+
+    #[test]
+    fn msgpack_compound_in_legacy_compound_fails() {
+        let inner = Message::Compound { msgs: vec![Message::Nack { seq: 1 }] };
+        let inner = WireFormat::MessagePack.codec().encode(&inner).unwrap();
+        let mut buf = Vec::new();
+        write_compound(&mut buf, &[inner]).unwrap();
+        match decode(buf.as_slice()) {
+            Err(Error::InvalidField(..)) => {},
+            result => panic!("Unexpected {}", result),
+        }
+    }
+
+    #[test]
+    fn garbage_never_panics() {
+        let mut rng = rng();
+        for _ in range(0u, 10000) {
+            let mut buf = random_bytes(&mut rng);
+            // Most MessagePack garbage should at least start with a map
+            if !buf.is_empty() && rng.gen() {
+                buf[0] = 0x80 | rng.gen_range(0u8, 16);
+            }
+            let _ = decode(buf.as_slice());
+        }
+    }
+
+    #[test]
+    fn corrupted_messages_never_panic() {
+        let mut rng = rng();
+        for _ in range(0u, 500) {
+            let msg = random_message(&mut rng, false);
+            let format = if rng.gen() { WireFormat::Legacy } else { WireFormat::MessagePack };
+            let buf = format.codec_for(&msg).encode(&msg).unwrap();
+            for _ in range(0u, 20) {
+                let mut corrupted = buf.clone();
+                let index = rng.gen_range(0, corrupted.len());
+                corrupted[index] = rng.gen();
+                let _ = decode(corrupted.as_slice());
+            }
+        }
+    }
+}
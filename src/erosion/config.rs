@@ -1,18 +1,46 @@
+use std::collections::BTreeMap;
 use std::time::duration::Duration;
 use std::io::net::ip::{
     Ipv4Addr,
+    Ipv6Addr,
     SocketAddr,
 };
+use std::sync::Arc;
+
+use codec::WireFormat;
+use delegate::{
+    AliveDelegate,
+    ConflictDelegate,
+    EventDelegate,
+    MergeDelegate,
+    PingDelegate,
+    QueryDelegate,
+};
 
 #[deriving(Clone)]
 pub struct Config {
     pub name: String,
+
+    /// The address to bind the UDP socket and the TCP listener to. The IPv6
+    /// wildcard `[::]` binds `0.0.0.0` on the same port as well, so IPv4
+    /// members are reached whatever the OS default of `IPV6_V6ONLY`, and
+    /// falls back to `0.0.0.0` alone on hosts without IPv6. Binding fails
+    /// if an IPv6 address would be advertised with the legacy wire format.
+    /// With port 0 the OS picks a free port, see `Membership::local_addr`.
     pub bind_addr: SocketAddr,
 
-    /// The timeout for establishing a TCP connection with a remote node for
-    /// a full state sync.
-    tcp_timeout: Duration,
+    /// The address other members reach us at, carried in our Alive messages.
+    /// If unset, `bind_addr` is advertised, unless it is a wildcard, in
+    /// which case the first private address of the local interfaces is.
+    /// Set it when the node is behind NAT or in a container.
+    pub advertise_addr: Option<SocketAddr>,
+
+    /// The timeout for establishing a TCP connection with a remote node,
+    /// and for sending or receiving a message over it.
+    pub tcp_timeout: Duration,
 
+    /// The number of nodes that will be asked to perform an indirect probe
+    /// of a node in the case a direct probe fails.
     pub indirect_checks: uint,
 
     /// The multiplier for the number of retransmissions that are attempted for
@@ -25,7 +53,7 @@ pub struct Config {
     /// This allows the retransmits to scale properly with cluster size. The
     /// higher the multiplier, the more likely a failed broadcast is to converge
     /// at the expense of increased bandwidth.
-    retransmit_mult: int,
+    pub retransmit_mult: int,
 
     /// The multiplier for determining the time an inaccessible node is
     /// considered suspect before declaring it dead.
@@ -39,7 +67,7 @@ pub struct Config {
     /// an inaccessible node is considered part of the cluster before declaring
     /// it dead, giving that suspect node more time to refute if it is indeed
     /// still alive.
-    suspicion_mult: int,
+    pub suspicion_mult: int,
 
     /// The interval between complete state syncs. Complete state syncs are
     /// done with a single node over TCP and are quite expensive relative to
@@ -61,6 +89,18 @@ pub struct Config {
     /// time) on your network.
     pub probe_timeout: Duration,
 
+    /// Tune the probe timeout automatically. Once enough round-trip times
+    /// are observed, twice the 99-percentile of the recent RTTs to all
+    /// members is used, bounded by half of `probe_interval`. It only ever
+    /// raises the timeout, `probe_timeout` stays the lower bound.
+    pub auto_probe_timeout: bool,
+
+    /// The upper limit of the local health score. Every missed nack from an
+    /// indirect probe helper raises the score, and probe timeouts and
+    /// intervals are scaled by `score + 1`, so a node with a broken network
+    /// slows down instead of wrongly declaring healthy members failed.
+    pub awareness_max_multiplier: int,
+
     /// The interval between sending messages that need
     /// to be gossiped that haven't been able to piggyback on probing messages.
     /// If this is set to zero, non-piggyback gossip is disabled. By lowering
@@ -74,6 +114,126 @@ pub struct Config {
     /// increased bandwidth.
     pub gossip_nodes: uint,
 
+    /// The time a dead or left member is kept in the member list, so news of
+    /// its death can still be gossiped, before it is reaped.
+    pub dead_reap_interval: Duration,
+
+    /// The time a reaped member is remembered as a tombstone. Alive messages
+    /// for a tombstoned member which are not newer than its last known
+    /// incarnation are stale and rejected.
+    pub tombstone_retention: Duration,
+
+    /// The time after which a dead or left member's name may be reclaimed by
+    /// a node with a different address. Setting this to zero will forbid
+    /// reclaiming names completely.
+    pub dead_node_reclaim_time: Duration,
+
+    /// The largest UDP packet we send, in bytes. Gossip fills packets up to
+    /// this size. It should fit into the MTU of the network path, minus the
+    /// IP and UDP headers, to avoid fragmentation. Larger packets from
+    /// members with a different size are still received, and counted by
+    /// `Membership::oversized_packets`. It must leave room for the label and
+    /// the compound header.
+    pub packet_size: uint,
+
+    /// Maintain a Vivaldi network coordinate of the local node from the RTT
+    /// of pings, and exchange coordinates in acks. This allows estimating
+    /// the RTT between any two members without probing them.
+    pub enable_coordinates: bool,
+
+    /// The encoding of the messages we send. Messages in either format are
+    /// always understood, so a cluster can be migrated one node at a time,
+    /// but members which predate MessagePack only understand the legacy
+    /// format. It is therefore the default, and MessagePack has to be
+    /// chosen explicitly once every member understands it. Members with
+    /// IPv6 addresses need the MessagePack format, so with the legacy
+    /// format a wildcard `bind_addr` only advertises an IPv4 address.
+    pub wire_format: WireFormat,
+
+    /// How long to wait for the member we join through to admit us.
+    pub join_timeout: Duration,
+
+    /// Consulted before a new member is added because of an Alive message,
+    /// and may veto it.
+    pub alive_delegate: Option<Arc<Box<AliveDelegate + Send + Sync>>>,
+
+    /// Consulted when a node joins through us, and may reject it. The
+    /// rejection is reported to the node, so its `join` fails.
+    pub merge_delegate: Option<Arc<Box<MergeDelegate + Send + Sync>>>,
+
+    /// The initial tags of the local node, see `Membership::set_tags` for
+    /// their limits.
+    pub tags: BTreeMap<String, String>,
+
+    /// Attaches a payload to the acks we send, and is told about the payload
+    /// and the RTT of every direct ack we receive.
+    pub ping_delegate: Option<Arc<Box<PingDelegate + Send + Sync>>>,
+
+    /// Identifies the cluster. It is prepended to every packet, and packets
+    /// with another label are dropped, so clusters sharing a network never
+    /// merge by accident. Empty for no label.
+    pub label: String,
+
+    /// Accept packets without a label even though `label` is set. Used
+    /// while adding a label to an existing cluster, one node at a time.
+    pub allow_unlabeled: bool,
+
+    /// Notified when another node claims our name, or the name of another
+    /// member, from a different address.
+    pub conflict_delegate: Option<Arc<Box<ConflictDelegate + Send + Sync>>>,
+
+    /// When another node claims our name, ask the other members which
+    /// address they know for it. If a strict majority of the answers names
+    /// the other node, the local node shuts down and
+    /// `ConflictDelegate::notify_conflict_lost` is called.
+    pub resolve_name_conflicts: bool,
+
+    /// Told about the user events broadcast in the cluster.
+    pub event_delegate: Option<Arc<Box<EventDelegate + Send + Sync>>>,
+
+    /// The number of Lamport times the received user events are remembered
+    /// for, to drop the copies gossip delivers. Events older than that are
+    /// dropped too, so it should cover the events broadcast while one
+    /// spreads through the cluster.
+    pub event_buffer_len: uint,
+
+    /// Coalesce the member events handed to the event delegate: they are
+    /// batched until none arrived for this period, and only the final state
+    /// of each member is delivered, if it differs from the last one
+    /// delivered. A member failing and coming back within a batch is never
+    /// reported. Zero delivers every event.
+    pub member_event_quiet_period: Duration,
+
+    /// The longest a member event is held back by coalescing, even if the
+    /// events keep coming. It must be at least `member_event_quiet_period`.
+    pub member_event_max_delay: Duration,
+
+    /// Coalesce the user events handed to the event delegate: of the events
+    /// of each name received within this period, only the one with the
+    /// latest Lamport time is delivered. Zero delivers every event.
+    pub coalesce_user_events: Duration,
+
+    /// The number of user events the local node may broadcast per second,
+    /// as each is retransmitted `retransmit_mult * log(N+1)` times. Zero for
+    /// no limit.
+    pub user_event_rate_limit: uint,
+
+    /// The largest encoded user event `broadcast` accepts, in bytes. It is
+    /// also bounded by the room in a packet, as events are piggybacked.
+    pub user_event_size_limit: uint,
+
+    /// Like `event_buffer_len`, for queries.
+    pub query_buffer_len: uint,
+
+    /// Answers the queries of other members. Without it, queries are only
+    /// acked.
+    pub query_delegate: Option<Arc<Box<QueryDelegate + Send + Sync>>>,
+
+    /// The number of random members the responses to our queries are also
+    /// relayed through, in case the direct path from a responder is broken.
+    /// Zero only has them sent directly.
+    pub query_relay_factor: u8,
+
     /// Used to control message compression. This can be used to reduce
     /// bandwidth usage at the cost of slightly more CPU utilization.
     enable_compression: bool,
@@ -90,9 +250,10 @@ pub fn lan(name: String) -> Config {
     Config {
         name: name,
         bind_addr: SocketAddr {
-            ip: Ipv4Addr(0, 0, 0, 0),
+            ip: Ipv6Addr(0, 0, 0, 0, 0, 0, 0, 0),
             port: 7201,
         },
+        advertise_addr: None,
         tcp_timeout: Duration::seconds(10),
         indirect_checks: 3,
         retransmit_mult: 4,
@@ -100,8 +261,35 @@ pub fn lan(name: String) -> Config {
         push_pull_interval: Duration::seconds(30),
         probe_interval: Duration::seconds(1),
         probe_timeout: Duration::milliseconds(500),
+        auto_probe_timeout: false,
+        awareness_max_multiplier: 8,
         gossip_interval: Duration::milliseconds(200),
         gossip_nodes: 3,
+        dead_reap_interval: Duration::seconds(30),
+        tombstone_retention: Duration::minutes(5),
+        dead_node_reclaim_time: Duration::zero(),
+        packet_size: 1400,
+        enable_coordinates: true,
+        wire_format: WireFormat::Legacy,
+        join_timeout: Duration::seconds(5),
+        tags: BTreeMap::new(),
+        ping_delegate: None,
+        label: String::new(),
+        allow_unlabeled: false,
+        alive_delegate: None,
+        merge_delegate: None,
+        conflict_delegate: None,
+        resolve_name_conflicts: false,
+        event_delegate: None,
+        event_buffer_len: 64,
+        member_event_quiet_period: Duration::zero(),
+        member_event_max_delay: Duration::seconds(5),
+        coalesce_user_events: Duration::zero(),
+        user_event_rate_limit: 10,
+        user_event_size_limit: 512,
+        query_buffer_len: 64,
+        query_delegate: None,
+        query_relay_factor: 0,
         enable_compression: true,
     }
 }
@@ -118,14 +306,22 @@ pub fn wan(name: String) -> Config {
     config.probe_timeout = Duration::seconds(3);
     config.gossip_interval = Duration::milliseconds(500);
     config.gossip_nodes = 4;
+    config.packet_size = 548;
+    config.join_timeout = Duration::seconds(15);
     config
 }
 
 /// Like `lan`, however it returns a configuration that is optimized for a
 /// local loopback environments. The default configuration is still very
-/// conservative and errs on the side of caution.
+/// conservative and errs on the side of caution. It binds to `127.0.0.1`,
+/// which is advertised as is, so it works on hosts without a private
+/// address.
 pub fn local(name: String) -> Config {
     let mut config = lan(name);
+    config.bind_addr = SocketAddr {
+        ip: Ipv4Addr(127, 0, 0, 1),
+        port: 7201,
+    };
     config.tcp_timeout = Duration::seconds(1);
     config.indirect_checks = 1;
     config.retransmit_mult = 2;
@@ -133,5 +329,7 @@ pub fn local(name: String) -> Config {
     config.push_pull_interval = Duration::seconds(15);
     config.probe_timeout = Duration::milliseconds(200);
     config.gossip_interval = Duration::milliseconds(100);
+    config.packet_size = 65507;
+    config.join_timeout = Duration::seconds(1);
     config
 }
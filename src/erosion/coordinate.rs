@@ -0,0 +1,342 @@
+use std::collections::{
+    HashMap,
+    RingBuf,
+};
+use std::error::FromError;
+use std::io::Writer;
+use std::num::Float;
+use std::rand::{
+    task_rng,
+    Rng,
+};
+use std::sync::{
+    Mutex,
+    RWLock,
+};
+use std::time::Duration;
+
+use error::{
+    Error,
+    ErosionResult,
+};
+
+/// The dimensionality of the coordinate system.
+const DIMENSIONALITY: uint = 8;
+
+/// The default error value when a node hasn't yet made any observations. It
+/// also serves as an upper limit on the error value.
+const VIVALDI_ERROR_MAX: f64 = 1.5;
+
+/// A tuning factor that controls the maximum impact an observation can have
+/// on a node's confidence.
+const VIVALDI_CE: f64 = 0.25;
+
+/// A tuning factor that controls the maximum impact an observation can have
+/// on a node's coordinate.
+const VIVALDI_CC: f64 = 0.25;
+
+/// The number of samples used to compute the adjustment term, which
+/// compensates for errors the Euclidean model can't capture.
+const ADJUSTMENT_WINDOW_SIZE: uint = 20;
+
+/// The minimum height, in seconds, of a coordinate.
+const HEIGHT_MIN: f64 = 10.0e-6;
+
+/// The number of RTT samples per node of which the median is used, to
+/// filter out spikes.
+const LATENCY_FILTER_SIZE: uint = 3;
+
+/// How strongly coordinates are pulled towards the origin, in seconds.
+/// Without it the whole coordinate system slowly drifts away.
+const GRAVITY_RHO: f64 = 150.0;
+
+/// Distances below this, in seconds, are treated as zero.
+const ZERO_THRESHOLD: f64 = 1.0e-6;
+
+/// A network coordinate, as computed by the Vivaldi algorithm. The distance
+/// between two coordinates estimates the round-trip time between the nodes.
+#[deriving(Clone, Show)]
+pub struct Coordinate {
+    /// Euclidean portion, in seconds
+    pub vec: Vec<f64>,
+
+    /// How confident the node is in its coordinate
+    pub error: f64,
+
+    /// Distance offset learned from observations, in seconds
+    pub adjustment: f64,
+
+    /// Distance offset of the node's access link, in seconds
+    pub height: f64,
+}
+
+impl Coordinate {
+    /// The coordinate of a node which hasn't made any observations yet.
+    pub fn new() -> Coordinate {
+        Coordinate {
+            vec: Vec::from_elem(DIMENSIONALITY, 0.0),
+            error: VIVALDI_ERROR_MAX,
+            adjustment: 0.0,
+            height: HEIGHT_MIN,
+        }
+    }
+
+    /// Returns the estimated round-trip time to `other`.
+    pub fn distance_to(&self, other: &Coordinate) -> Duration {
+        let dist = self.raw_distance_to(other);
+        let adjusted = dist + self.adjustment + other.adjustment;
+        let dist = if adjusted > 0.0 { adjusted } else { dist };
+        Duration::nanoseconds((dist * 1.0e9) as i64)
+    }
+
+    pub fn is_valid(&self) -> bool {
+        self.vec.len() == DIMENSIONALITY
+        && self.vec.iter().all(|x| x.is_finite())
+        && self.error.is_finite()
+        && self.adjustment.is_finite()
+        && self.height.is_finite()
+    }
+
+    pub fn write<W: Writer>(&self, writer: &mut W) -> ErosionResult<()> {
+        if let Err(e) = writer.write_u8(self.vec.len() as u8) {
+            return Err(FromError::from_error(e));
+        }
+        for x in self.vec.iter() {
+            if let Err(e) = writer.write_be_f64(*x) {
+                return Err(FromError::from_error(e));
+            }
+        }
+        if let Err(e) = writer.write_be_f64(self.error) {
+            return Err(FromError::from_error(e));
+        }
+        if let Err(e) = writer.write_be_f64(self.adjustment) {
+            return Err(FromError::from_error(e));
+        }
+        if let Err(e) = writer.write_be_f64(self.height) {
+            return Err(FromError::from_error(e));
+        }
+        Ok(())
+    }
+
+    pub fn read<R: Reader>(reader: &mut R) -> ErosionResult<Coordinate> {
+        let dimensionality = reader.read_u8();
+        if let Err(e) = dimensionality {
+            return Err(FromError::from_error(e));
+        }
+
+        let mut vec = Vec::new();
+        for _ in range(0, dimensionality.unwrap()) {
+            match reader.read_be_f64() {
+                Ok(x) => vec.push(x),
+                Err(e) => return Err(FromError::from_error(e)),
+            }
+        }
+
+        let error = reader.read_be_f64();
+        if let Err(e) = error {
+            return Err(FromError::from_error(e));
+        }
+
+        let adjustment = reader.read_be_f64();
+        if let Err(e) = adjustment {
+            return Err(FromError::from_error(e));
+        }
+
+        let height = reader.read_be_f64();
+        if let Err(e) = height {
+            return Err(FromError::from_error(e));
+        }
+
+        let coord = Coordinate {
+            vec: vec,
+            error: error.unwrap(),
+            adjustment: adjustment.unwrap(),
+            height: height.unwrap(),
+        };
+        if !coord.is_valid() {
+            return Err(Error::InvalidField("coordinate"));
+        }
+        Ok(coord)
+    }
+
+    fn raw_distance_to(&self, other: &Coordinate) -> f64 {
+        magnitude(diff(self.vec.as_slice(), other.vec.as_slice()).as_slice())
+        + self.height + other.height
+    }
+
+    /// Returns the coordinate moved by `force` away from `other`, or towards
+    /// it for a negative force.
+    fn apply_force(&self, force: f64, other: &Coordinate) -> Coordinate {
+        let mut ret = self.clone();
+        let (unit, mag) = unit_vector_at(self.vec.as_slice(), other.vec.as_slice());
+        ret.vec = add(ret.vec.as_slice(), mul(unit.as_slice(), force).as_slice());
+        if mag > ZERO_THRESHOLD {
+            ret.height = (ret.height + other.height) * force / mag + ret.height;
+            ret.height = ret.height.max(HEIGHT_MIN);
+        }
+        ret
+    }
+}
+
+/// Maintains the local coordinate, and updates it from observed round-trip
+/// times to other nodes.
+struct Client {
+    coord: Coordinate,
+
+    /// The origin, which gravity pulls towards
+    origin: Coordinate,
+
+    /// Recent samples of `rtt - distance`, used for the adjustment term
+    adjustment_samples: RingBuf<f64>,
+
+    /// Recent RTT samples per node, in seconds
+    latency_filters: HashMap<String, RingBuf<f64>>,
+}
+
+impl Client {
+    fn new() -> Client {
+        Client {
+            coord: Coordinate::new(),
+            origin: Coordinate::new(),
+            adjustment_samples: RingBuf::with_capacity(ADJUSTMENT_WINDOW_SIZE),
+            latency_filters: HashMap::new(),
+        }
+    }
+
+    fn update(&mut self, name: &str, other: &Coordinate, rtt: Duration) {
+        let rtt = rtt.num_nanoseconds().unwrap_or(0) as f64 * 1.0e-9;
+        let rtt = self.latency_filter(name, rtt);
+        self.update_vivaldi(other, rtt);
+        self.update_adjustment(other, rtt);
+        self.update_gravity();
+
+        // Never let a bad observation poison the coordinate
+        if !self.coord.is_valid() {
+            warn!("Network coordinate became invalid, resetting it");
+            self.coord = Coordinate::new();
+        }
+    }
+
+    /// Returns the median of the recent RTT samples to `name`.
+    fn latency_filter(&mut self, name: &str, rtt: f64) -> f64 {
+        if !self.latency_filters.contains_key(name) {
+            self.latency_filters.insert(name.to_string(),
+                                        RingBuf::with_capacity(LATENCY_FILTER_SIZE));
+        }
+
+        let samples = self.latency_filters.get_mut(name).unwrap();
+        if samples.len() == LATENCY_FILTER_SIZE {
+            samples.pop_front();
+        }
+        samples.push_back(rtt);
+
+        let mut sorted: Vec<f64> = samples.iter().map(|&rtt| rtt).collect();
+        sorted.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        sorted[sorted.len() / 2]
+    }
+
+    fn update_vivaldi(&mut self, other: &Coordinate, rtt: f64) {
+        let rtt = rtt.max(ZERO_THRESHOLD);
+        let dist = self.coord.distance_to(other).num_nanoseconds().unwrap_or(0) as f64 * 1.0e-9;
+
+        let wrongness = (dist - rtt).abs() / rtt;
+        let total_error = (self.coord.error + other.error).max(ZERO_THRESHOLD);
+        let weight = self.coord.error / total_error;
+
+        self.coord.error = VIVALDI_CE * weight * wrongness
+                           + self.coord.error * (1.0 - VIVALDI_CE * weight);
+        self.coord.error = self.coord.error.min(VIVALDI_ERROR_MAX);
+
+        let force = VIVALDI_CC * weight * (rtt - dist);
+        self.coord = self.coord.apply_force(force, other);
+    }
+
+    fn update_adjustment(&mut self, other: &Coordinate, rtt: f64) {
+        if self.adjustment_samples.len() == ADJUSTMENT_WINDOW_SIZE {
+            self.adjustment_samples.pop_front();
+        }
+        self.adjustment_samples.push_back(rtt - self.coord.raw_distance_to(other));
+
+        let sum = self.adjustment_samples.iter().fold(0.0, |sum, sample| sum + *sample);
+        self.coord.adjustment = sum / (2.0 * ADJUSTMENT_WINDOW_SIZE as f64);
+    }
+
+    fn update_gravity(&mut self) {
+        let dist = self.origin.raw_distance_to(&self.coord);
+        let force = -1.0 * (dist / GRAVITY_RHO).powi(2);
+        self.coord = self.coord.apply_force(force, &self.origin);
+    }
+}
+
+/// The local network coordinate, and the latest known coordinates of the
+/// other members.
+pub struct Coordinates {
+    client: Mutex<Client>,
+
+    members: RWLock<HashMap<String, Coordinate>>,
+}
+
+impl Coordinates {
+    pub fn new() -> Coordinates {
+        Coordinates {
+            client: Mutex::new(Client::new()),
+            members: RWLock::new(HashMap::new()),
+        }
+    }
+
+    pub fn local(&self) -> Coordinate {
+        self.client.lock().coord.clone()
+    }
+
+    pub fn get(&self, name: &str) -> Option<Coordinate> {
+        self.members.read().get(name).map(|coord| coord.clone())
+    }
+
+    /// Update the local coordinate from a ping to `name`, which answered
+    /// with `coord` after `rtt`.
+    pub fn update(&self, name: &str, coord: Coordinate, rtt: Duration) {
+        self.client.lock().update(name, &coord, rtt);
+        self.members.write().insert(name.to_string(), coord);
+    }
+
+    pub fn remove(&self, name: &str) {
+        self.client.lock().latency_filters.remove(name);
+        self.members.write().remove(name);
+    }
+}
+
+fn add(a: &[f64], b: &[f64]) -> Vec<f64> {
+    a.iter().zip(b.iter()).map(|(x, y)| *x + *y).collect()
+}
+
+fn diff(a: &[f64], b: &[f64]) -> Vec<f64> {
+    a.iter().zip(b.iter()).map(|(x, y)| *x - *y).collect()
+}
+
+fn mul(v: &[f64], factor: f64) -> Vec<f64> {
+    v.iter().map(|x| *x * factor).collect()
+}
+
+fn magnitude(v: &[f64]) -> f64 {
+    v.iter().fold(0.0, |sum, x| sum + *x * *x).sqrt()
+}
+
+/// Returns the unit vector pointing at `a` from `b`, and the distance
+/// between them. A random direction is picked if they are on top of each
+/// other.
+fn unit_vector_at(a: &[f64], b: &[f64]) -> (Vec<f64>, f64) {
+    let ret = diff(a, b);
+    let mag = magnitude(ret.as_slice());
+    if mag > ZERO_THRESHOLD {
+        return (mul(ret.as_slice(), 1.0 / mag), mag);
+    }
+
+    let mut rng = task_rng();
+    let ret: Vec<f64> = range(0, a.len()).map(|_| rng.gen::<f64>() - 0.5).collect();
+    let mag = magnitude(ret.as_slice());
+    if mag > ZERO_THRESHOLD {
+        return (mul(ret.as_slice(), 1.0 / mag), 0.0);
+    }
+
+    (Vec::from_elem(a.len(), 0.0), 0.0)
+}
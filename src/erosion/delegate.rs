@@ -0,0 +1,84 @@
+use std::time::Duration;
+
+use member::Member;
+
+/// The longest payload a `PingDelegate` may attach to an ack, in bytes.
+/// Longer payloads are dropped.
+pub const MAX_PING_PAYLOAD_LEN: uint = 512;
+
+/// Notified when two nodes claim the same name.
+pub trait ConflictDelegate {
+    /// `other` claims the name of `existing` from a different address.
+    fn notify_conflict(&self, existing: &Member, other: &Member);
+
+    /// The cluster believes `winner` owns our name, so the local node has
+    /// been shut down. To rejoin under another name, bind a new
+    /// `Membership` with a different `Config::name`.
+    fn notify_conflict_lost(&self, winner: &Member) {
+        let _ = winner;
+    }
+}
+
+/// Decides whether a new node may be added to the member list.
+pub trait AliveDelegate {
+    /// `member` is about to be added because of an Alive message. Returning
+    /// an error vetoes it, and the reason is reported to the node if it is
+    /// joining through us.
+    fn notify_alive(&self, member: &Member) -> Result<(), String>;
+}
+
+/// Decides whether the members known to a joining node may be merged into
+/// ours.
+pub trait MergeDelegate {
+    /// `members` ask to be merged. Returning an error rejects them all, and
+    /// the reason is reported to the joining node.
+    fn notify_merge(&self, members: &[Member]) -> Result<(), String>;
+}
+
+/// Piggybacks application data on the acks of the failure detector.
+pub trait PingDelegate {
+    /// Returns the payload to attach to the acks we send, at most
+    /// `MAX_PING_PAYLOAD_LEN` bytes.
+    fn ack_payload(&self) -> Vec<u8>;
+
+    /// `member` answered our ping directly after `rtt`, with `payload`
+    /// attached.
+    fn notify_ping_complete(&self, member: &Member, rtt: Duration, payload: &[u8]);
+}
+
+/// Told about the members joining, leaving or being updated, and about the
+/// user events of the cluster, see `Membership::broadcast`. Member events
+/// may be coalesced, see `Config::member_event_quiet_period`.
+pub trait EventDelegate {
+    /// `member` joined, or came back after it left or died.
+    fn notify_join(&self, member: &Member) {
+        let _ = member;
+    }
+
+    /// `member` left, or died if its state is `Dead`.
+    fn notify_leave(&self, member: &Member) {
+        let _ = member;
+    }
+
+    /// The tags of `member` changed.
+    fn notify_update(&self, member: &Member) {
+        let _ = member;
+    }
+
+    /// The event `name` with `payload` was broadcast at the Lamport time
+    /// `ltime`. Events may arrive out of order, but each only once, and
+    /// older events of a name may be skipped, see
+    /// `Config::coalesce_user_events`.
+    fn notify_user_event(&self, ltime: u64, name: &str, payload: &[u8]) {
+        let _ = (ltime, name, payload);
+    }
+}
+
+/// Answers the queries of other members, see `Membership::query`.
+pub trait QueryDelegate {
+    /// The member `from` asks `name` with `payload`. The response, if any,
+    /// is sent back to it, and must be at most `MAX_QUERY_PAYLOAD_LEN`
+    /// bytes. Called on the thread handling messages, so it should be
+    /// quick.
+    fn handle_query(&self, from: &str, name: &str, payload: &[u8]) -> Option<Vec<u8>>;
+}
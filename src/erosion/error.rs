@@ -0,0 +1,138 @@
+use std::error;
+use std::error::FromError;
+use std::fmt;
+use std::io::{
+    IoError,
+    IoErrorKind,
+};
+use std::io::net::ip::SocketAddr;
+
+pub type ErosionResult<T> = Result<T, Error>;
+
+pub enum Error {
+    // Setup
+
+    /// Failed to bind a network listener
+    Bind {
+        addr: SocketAddr,
+        err: IoError,
+    },
+
+    /// Failed to join a cluster
+    Join(String),
+
+    /// Bound to a wildcard address, but no private address was found to
+    /// advertise instead
+    NoAdvertiseAddr,
+
+    /// A configuration value is out of range
+    InvalidConfig(String),
+
+    // Encoding and decoding
+
+    /// The message type is unknown
+    UnknownMessageType(u8),
+
+    /// The message can not be encoded
+    UnsupportedMessage,
+
+    /// The message ended before it was completely decoded
+    Truncated,
+
+    /// A string is not valid UTF-8
+    InvalidUtf8,
+
+    /// A field is longer than it may be
+    TooLong {
+        field: &'static str,
+        len: uint,
+        max: uint,
+    },
+
+    /// A field holds a value which can not be encoded or decoded
+    InvalidField(&'static str),
+
+    // Network
+
+    /// Failed to send or receive packets
+    Transport(IoError),
+
+    /// The remote node didn't answer in time
+    Timeout,
+
+    /// A packet carries another cluster label than ours, empty if it
+    /// carries none
+    LabelMismatch(String),
+
+    /// Too many user events were broadcast recently
+    RateLimited,
+}
+
+impl fmt::Show for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            &Error::Bind { ref addr, ref err } => {
+                write!(f, "Failed to bind to {}. Err: {}", addr, err)
+            },
+            &Error::Join(ref reason) => write!(f, "Failed to join. {}", reason),
+            &Error::NoAdvertiseAddr => {
+                write!(f, "No private address found to advertise, set advertise_addr")
+            },
+            &Error::InvalidConfig(ref reason) => write!(f, "Invalid configuration. {}", reason),
+            &Error::UnknownMessageType(t) => write!(f, "Unknown message type {}", t),
+            &Error::UnsupportedMessage => write!(f, "Message not supported"),
+            &Error::Truncated => write!(f, "Message is truncated"),
+            &Error::InvalidUtf8 => write!(f, "Not a valid UTF8 string"),
+            &Error::TooLong { field, len, max } => {
+                write!(f, "{} is too long ({} bytes, at most {})", field, len, max)
+            },
+            &Error::InvalidField(field) => write!(f, "Invalid {}", field),
+            &Error::Transport(ref err) => write!(f, "Transport error. Err: {}", err),
+            &Error::Timeout => write!(f, "Timed out"),
+            &Error::LabelMismatch(ref label) => {
+                write!(f, "Cluster label `{}` doesn't match ours", label)
+            },
+            &Error::RateLimited => write!(f, "Too many user events, try again later"),
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn description(&self) -> &str {
+        match self {
+            &Error::Bind { .. } => "failed to bind",
+            &Error::Join(..) => "failed to join",
+            &Error::NoAdvertiseAddr => "no address to advertise",
+            &Error::InvalidConfig(..) => "invalid configuration",
+            &Error::UnknownMessageType(..) => "unknown message type",
+            &Error::UnsupportedMessage => "message not supported",
+            &Error::Truncated => "message is truncated",
+            &Error::InvalidUtf8 => "not a valid UTF8 string",
+            &Error::TooLong { .. } => "field is too long",
+            &Error::InvalidField(..) => "invalid field",
+            &Error::Transport(..) => "transport error",
+            &Error::Timeout => "timed out",
+            &Error::LabelMismatch(..) => "cluster label mismatch",
+            &Error::RateLimited => "rate limited",
+        }
+    }
+
+    fn cause(&self) -> Option<&error::Error> {
+        match self {
+            &Error::Bind { ref err, .. } => Some(err as &error::Error),
+            &Error::Transport(ref err) => Some(err as &error::Error),
+            _ => None,
+        }
+    }
+}
+
+/// Running out of input while decoding means the message is truncated, any
+/// other I/O error comes from the network.
+impl FromError<IoError> for Error {
+    fn from_error(err: IoError) -> Error {
+        match err.kind {
+            IoErrorKind::EndOfFile => Error::Truncated,
+            _ => Error::Transport(err),
+        }
+    }
+}
@@ -0,0 +1,519 @@
+use std::cmp;
+use std::collections::{
+    HashMap,
+    RingBuf,
+};
+use std::io::timer::Timer;
+use std::sync::{
+    Arc,
+    Mutex,
+};
+use std::thread::Thread;
+use std::time::Duration;
+
+use time;
+use time::Timespec;
+
+use delegate::EventDelegate;
+
+use member::Member;
+
+struct UserEvent {
+    ltime: u64,
+    name: String,
+    payload: Vec<u8>,
+}
+
+/// Hands user events to the event delegate in batches. Of the events of
+/// each name received within `period` of the first event of a batch, only
+/// the latest one is delivered.
+#[deriving(Clone)]
+pub struct UserEventCoalescer {
+    events: Sender<UserEvent>,
+}
+
+impl UserEventCoalescer {
+    pub fn start(period: Duration,
+                 delegate: Arc<Box<EventDelegate + Send + Sync>>) -> UserEventCoalescer {
+        let (tx, rx) = channel();
+        Thread::spawn(move || {
+            coalesce_user_events(period, rx, delegate);
+        }).detach();
+
+        UserEventCoalescer {
+            events: tx,
+        }
+    }
+
+    pub fn push(&self, ltime: u64, name: String, payload: Vec<u8>) {
+        let _ = self.events.send_opt(UserEvent {
+            ltime: ltime,
+            name: name,
+            payload: payload,
+        });
+    }
+}
+
+fn coalesce_user_events(period: Duration, events: Receiver<UserEvent>,
+                        delegate: Arc<Box<EventDelegate + Send + Sync>>) {
+    let mut timer = Timer::new().unwrap();
+    loop {
+        // The first event starts a batch
+        let first = match events.recv_opt() {
+            Ok(event) => event,
+            Err(()) => return,
+        };
+        let mut latest = HashMap::new();
+        keep_latest(&mut latest, first);
+
+        let flush = timer.oneshot(period);
+        let mut stopped = false;
+        loop {
+            select!(
+                event = events.recv_opt() => match event {
+                    Ok(event) => keep_latest(&mut latest, event),
+                    // Every coalescer is gone, deliver what we have
+                    Err(()) => {
+                        stopped = true;
+                        break;
+                    },
+                },
+                () = flush.recv() => break
+            )
+        }
+
+        let mut batch: Vec<UserEvent> = latest.into_iter().map(|(_, event)| event).collect();
+        batch.sort_by(|a, b| a.ltime.cmp(&b.ltime));
+        for event in batch.iter() {
+            delegate.notify_user_event(event.ltime, event.name.as_slice(),
+                                       event.payload.as_slice());
+        }
+
+        if stopped {
+            return;
+        }
+    }
+}
+
+fn keep_latest(latest: &mut HashMap<String, UserEvent>, event: UserEvent) {
+    let newer = match latest.get(&event.name) {
+        Some(kept) => event.ltime > kept.ltime,
+        None => true,
+    };
+    if newer {
+        latest.insert(event.name.clone(), event);
+    }
+}
+
+/// What happened to a member, as told to the event delegate.
+#[deriving(Copy, Clone, PartialEq, Show)]
+pub enum MemberEventKind {
+    Join,
+    Leave,
+    Update,
+}
+
+/// Hand a member event to the matching method of `delegate`.
+pub fn notify_member_event(delegate: &Arc<Box<EventDelegate + Send + Sync>>,
+                           kind: MemberEventKind, member: &Member) {
+    match kind {
+        MemberEventKind::Join => delegate.notify_join(member),
+        MemberEventKind::Leave => delegate.notify_leave(member),
+        MemberEventKind::Update => delegate.notify_update(member),
+    }
+}
+
+/// Hands member events to the event delegate in batches. A batch is over
+/// once no event arrived for `quiet_period`, or `max_delay` after its first
+/// event. Only the final state of each member is delivered, and only if it
+/// differs from the last one delivered, so a member which leaves and comes
+/// back within a batch is never reported.
+#[deriving(Clone)]
+pub struct MemberEventCoalescer {
+    events: Sender<(MemberEventKind, Member)>,
+}
+
+impl MemberEventCoalescer {
+    pub fn start(quiet_period: Duration, max_delay: Duration,
+                 delegate: Arc<Box<EventDelegate + Send + Sync>>) -> MemberEventCoalescer {
+        let (tx, rx) = channel();
+        Thread::spawn(move || {
+            coalesce_member_events(quiet_period, max_delay, rx, delegate);
+        }).detach();
+
+        MemberEventCoalescer {
+            events: tx,
+        }
+    }
+
+    pub fn push(&self, kind: MemberEventKind, member: Member) {
+        let _ = self.events.send_opt((kind, member));
+    }
+}
+
+fn coalesce_member_events(quiet_period: Duration, max_delay: Duration,
+                          events: Receiver<(MemberEventKind, Member)>,
+                          delegate: Arc<Box<EventDelegate + Send + Sync>>) {
+    let quiet_period = quiet_period.num_nanoseconds().unwrap_or(0) as u64;
+    let max_delay = max_delay.num_nanoseconds().unwrap_or(0) as u64;
+    let mut timer = Timer::new().unwrap();
+
+    // The last join or leave delivered for each member which is not gone
+    let mut delivered: HashMap<String, MemberEventKind> = HashMap::new();
+
+    loop {
+        // The first event starts a batch
+        let (kind, member) = match events.recv_opt() {
+            Ok(event) => event,
+            Err(()) => return,
+        };
+        let mut batch = MemberBatch::new(time::precise_time_ns());
+        batch.push(kind, member, time::precise_time_ns());
+
+        let mut stopped = false;
+        loop {
+            let end = batch.end(quiet_period, max_delay);
+            let now = time::precise_time_ns();
+            if now >= end {
+                break;
+            }
+
+            let flush = timer.oneshot(Duration::nanoseconds((end - now) as i64));
+            select!(
+                event = events.recv_opt() => match event {
+                    Ok((kind, member)) => batch.push(kind, member, time::precise_time_ns()),
+                    // Every coalescer is gone, deliver what we have
+                    Err(()) => {
+                        stopped = true;
+                        break;
+                    },
+                },
+                () = flush.recv() => break
+            )
+        }
+
+        for &(kind, ref member) in settle(batch.latest, &mut delivered).iter() {
+            notify_member_event(&delegate, kind, member);
+        }
+
+        if stopped {
+            return;
+        }
+    }
+}
+
+/// The member events of a batch, and when they arrived. Times are in
+/// nanoseconds, as returned by `time::precise_time_ns`.
+struct MemberBatch {
+    latest: HashMap<String, (MemberEventKind, Member)>,
+    started: u64,
+    last_event: u64,
+}
+
+impl MemberBatch {
+    fn new(now: u64) -> MemberBatch {
+        MemberBatch {
+            latest: HashMap::new(),
+            started: now,
+            last_event: now,
+        }
+    }
+
+    fn push(&mut self, kind: MemberEventKind, member: Member, now: u64) {
+        self.last_event = now;
+        keep_final(&mut self.latest, kind, member);
+    }
+
+    /// Returns when the batch is over: `quiet_period` after its last event,
+    /// but no later than `max_delay` after it started.
+    fn end(&self, quiet_period: u64, max_delay: u64) -> u64 {
+        cmp::min(self.last_event + quiet_period, self.started + max_delay)
+    }
+}
+
+/// Returns the events of a batch to deliver: the final event of each member,
+/// unless it is the join or leave delivered last for it. `delivered` is
+/// updated with the returned events.
+fn settle(latest: HashMap<String, (MemberEventKind, Member)>,
+          delivered: &mut HashMap<String, MemberEventKind>) -> Vec<(MemberEventKind, Member)> {
+    let mut events = Vec::new();
+    for (name, (kind, member)) in latest.into_iter() {
+        // Members we never reported count as gone
+        let previous = delivered.get(&name).map_or(MemberEventKind::Leave, |kind| *kind);
+        if kind != MemberEventKind::Update && kind == previous {
+            continue;
+        }
+
+        match kind {
+            MemberEventKind::Join => {
+                delivered.insert(name, kind);
+            },
+            MemberEventKind::Leave => {
+                delivered.remove(&name);
+            },
+            MemberEventKind::Update => {},
+        }
+        events.push((kind, member));
+    }
+    events
+}
+
+/// Keep the latest event of the member. An update doesn't hide a join or a
+/// leave earlier in the batch, it only refreshes the member.
+fn keep_final(latest: &mut HashMap<String, (MemberEventKind, Member)>,
+              kind: MemberEventKind, member: Member) {
+    let kind = match latest.get(&member.name) {
+        Some(&(earlier, _)) if kind == MemberEventKind::Update => earlier,
+        _ => kind,
+    };
+    latest.insert(member.name.clone(), (kind, member));
+}
+
+/// Limits the number of events per period, e.g. the user events the local
+/// node broadcasts. The period slides, so no span of `period` ever holds
+/// more than `limit` events, not even across the end of a period.
+pub struct RateLimiter {
+    /// The events allowed per period, zero for no limit
+    limit: uint,
+
+    period: Duration,
+
+    /// The times of the events allowed within the last period, oldest first
+    events: Mutex<RingBuf<Timespec>>,
+}
+
+impl RateLimiter {
+    pub fn new(limit: uint, period: Duration) -> RateLimiter {
+        RateLimiter {
+            limit: limit,
+            period: period,
+            events: Mutex::new(RingBuf::with_capacity(limit)),
+        }
+    }
+
+    /// Count an event. Returns `false`, without counting it, if `limit`
+    /// events happened within the last period.
+    pub fn allow(&self) -> bool {
+        self.allow_at(time::get_time())
+    }
+
+    fn allow_at(&self, now: Timespec) -> bool {
+        if self.limit == 0 {
+            return true;
+        }
+
+        let mut events = self.events.lock();
+        // Forget the events which are a period old
+        while events.front().map_or(false, |&time| now - time >= self.period) {
+            events.pop_front();
+        }
+
+        if events.len() >= self.limit {
+            return false;
+        }
+        events.push_back(now);
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::{
+        BTreeMap,
+        HashMap,
+    };
+    use std::io::net::ip::{
+        Ipv4Addr,
+        SocketAddr,
+    };
+    use std::time::Duration;
+
+    use time;
+    use time::Timespec;
+
+    use member::{
+        Member,
+        MemberState,
+    };
+
+    use super::{
+        MemberBatch,
+        MemberEventKind,
+        RateLimiter,
+        UserEvent,
+        keep_final,
+        keep_latest,
+        settle,
+    };
+
+    fn member(name: &str) -> Member {
+        Member {
+            name: name.to_string(),
+            addr: SocketAddr {
+                ip: Ipv4Addr(127, 0, 0, 1),
+                port: 7201,
+            },
+            state: MemberState::Alive,
+            inc: 0,
+            state_change: time::get_time(),
+            ltime: 0,
+            tags: BTreeMap::new(),
+        }
+    }
+
+    /// Settle a batch of `events`, and return the kinds and names of the
+    /// events to deliver.
+    fn settle_batch(events: &[(MemberEventKind, &str)],
+                    delivered: &mut HashMap<String, MemberEventKind>)
+                    -> Vec<(MemberEventKind, String)> {
+        let mut latest = HashMap::new();
+        for &(kind, name) in events.iter() {
+            keep_final(&mut latest, kind, member(name));
+        }
+        settle(latest, delivered).into_iter().map(|(kind, member)| (kind, member.name)).collect()
+    }
+
+    fn at(ms: i64) -> Timespec {
+        Timespec::new(ms / 1000, (ms % 1000 * 1_000_000) as i32)
+    }
+
+    fn event(ltime: u64, name: &str) -> UserEvent {
+        UserEvent {
+            ltime: ltime,
+            name: name.to_string(),
+            payload: vec![ltime as u8],
+        }
+    }
+
+    #[test]
+    fn rate_limiter_allows_the_limit_per_period() {
+        let limiter = RateLimiter::new(2, Duration::seconds(1));
+        assert!(limiter.allow_at(at(0)));
+        assert!(limiter.allow_at(at(100)));
+        assert!(!limiter.allow_at(at(200)));
+
+        // The first event is a period old
+        assert!(limiter.allow_at(at(1000)));
+        assert!(!limiter.allow_at(at(1050)));
+        assert!(limiter.allow_at(at(1100)));
+    }
+
+    #[test]
+    fn rate_limiter_slides_across_periods() {
+        // A fixed window would allow 4 events between 900 and 1100
+        let limiter = RateLimiter::new(2, Duration::seconds(1));
+        assert!(limiter.allow_at(at(900)));
+        assert!(limiter.allow_at(at(950)));
+        assert!(!limiter.allow_at(at(1050)));
+        assert!(!limiter.allow_at(at(1100)));
+        assert!(limiter.allow_at(at(1900)));
+    }
+
+    #[test]
+    fn refused_events_are_not_counted() {
+        let limiter = RateLimiter::new(1, Duration::seconds(1));
+        assert!(limiter.allow_at(at(0)));
+        assert!(!limiter.allow_at(at(500)));
+        assert!(limiter.allow_at(at(1000)));
+    }
+
+    #[test]
+    fn zero_limit_allows_everything() {
+        let limiter = RateLimiter::new(0, Duration::seconds(1));
+        for ms in range(0, 100) {
+            assert!(limiter.allow_at(at(ms)));
+        }
+    }
+
+    #[test]
+    fn keep_latest_keeps_the_highest_ltime_per_name() {
+        let mut latest = HashMap::new();
+        keep_latest(&mut latest, event(2, "deploy"));
+        keep_latest(&mut latest, event(1, "deploy"));
+        keep_latest(&mut latest, event(3, "restart"));
+        keep_latest(&mut latest, event(4, "restart"));
+
+        assert_eq!(latest.len(), 2);
+        assert_eq!(latest.get(&"deploy".to_string()).unwrap().ltime, 2);
+        assert_eq!(latest.get(&"restart".to_string()).unwrap().ltime, 4);
+        assert_eq!(latest.get(&"restart".to_string()).unwrap().payload, vec![4]);
+    }
+
+    #[test]
+    fn keep_latest_keeps_the_first_of_equal_ltimes() {
+        let mut latest = HashMap::new();
+        keep_latest(&mut latest, event(2, "deploy"));
+        keep_latest(&mut latest, UserEvent {
+            ltime: 2,
+            name: "deploy".to_string(),
+            payload: vec![9],
+        });
+        assert_eq!(latest.get(&"deploy".to_string()).unwrap().payload, vec![2]);
+    }
+
+    #[test]
+    fn repeated_join_reports_nothing() {
+        let mut delivered = HashMap::new();
+        assert_eq!(settle_batch(&[(MemberEventKind::Join, "a")], &mut delivered),
+                   vec![(MemberEventKind::Join, "a".to_string())]);
+        assert!(settle_batch(&[(MemberEventKind::Join, "a")], &mut delivered).is_empty());
+    }
+
+    #[test]
+    fn dead_alive_flap_reports_nothing() {
+        let mut delivered = HashMap::new();
+        settle_batch(&[(MemberEventKind::Join, "a")], &mut delivered);
+
+        let batch = [(MemberEventKind::Leave, "a"), (MemberEventKind::Join, "a")];
+        assert!(settle_batch(&batch, &mut delivered).is_empty());
+
+        // It is still reported joined, so leaving later is delivered
+        assert_eq!(settle_batch(&[(MemberEventKind::Leave, "a")], &mut delivered),
+                   vec![(MemberEventKind::Leave, "a".to_string())]);
+    }
+
+    #[test]
+    fn unreported_member_leaving_reports_nothing() {
+        let mut delivered = HashMap::new();
+        let batch = [(MemberEventKind::Join, "a"), (MemberEventKind::Leave, "a")];
+        assert!(settle_batch(&batch, &mut delivered).is_empty());
+        assert!(delivered.is_empty());
+    }
+
+    #[test]
+    fn update_keeps_the_join_of_its_batch() {
+        let mut delivered = HashMap::new();
+        let batch = [(MemberEventKind::Join, "a"), (MemberEventKind::Update, "a")];
+        assert_eq!(settle_batch(&batch, &mut delivered),
+                   vec![(MemberEventKind::Join, "a".to_string())]);
+        assert_eq!(settle_batch(&[(MemberEventKind::Update, "a")], &mut delivered),
+                   vec![(MemberEventKind::Update, "a".to_string())]);
+    }
+
+    #[test]
+    fn batch_ends_after_the_quiet_period() {
+        let ms = 1_000_000;
+        let mut batch = MemberBatch::new(0);
+        batch.push(MemberEventKind::Join, member("a"), 0);
+        assert_eq!(batch.end(100 * ms, 300 * ms), 100 * ms);
+
+        batch.push(MemberEventKind::Join, member("b"), 50 * ms);
+        assert_eq!(batch.end(100 * ms, 300 * ms), 150 * ms);
+    }
+
+    #[test]
+    fn batch_ends_at_max_delay_while_events_keep_coming() {
+        // An event every 20ms never lets the quiet period pass
+        let ms = 1_000_000;
+        let mut batch = MemberBatch::new(0);
+        let mut now = 0;
+        while now < batch.end(100 * ms, 300 * ms) {
+            batch.push(MemberEventKind::Join, member(format!("node-{}", now / ms).as_slice()),
+                       now);
+            now += 20 * ms;
+        }
+
+        assert_eq!(batch.end(100 * ms, 300 * ms), 300 * ms);
+        assert_eq!(batch.latest.len(), 15);
+    }
+}
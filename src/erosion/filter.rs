@@ -0,0 +1,156 @@
+use regex::Regex;
+
+use member::Member;
+
+/// How a name or a tag value is matched.
+#[deriving(Clone, Show)]
+pub enum Pattern {
+    Exact(String),
+    Prefix(String),
+    Regex(Regex),
+}
+
+impl Pattern {
+    pub fn is_match(&self, s: &str) -> bool {
+        match self {
+            &Pattern::Exact(ref exact) => s == exact.as_slice(),
+            &Pattern::Prefix(ref prefix) => s.starts_with(prefix.as_slice()),
+            &Pattern::Regex(ref regex) => regex.is_match(s),
+        }
+    }
+}
+
+/// Selects members by name and tags. A member matches if every given
+/// pattern matches; a tag the member doesn't have never matches.
+#[deriving(Clone, Show)]
+pub struct Filter {
+    pub name: Option<Pattern>,
+
+    /// Tag keys and the patterns their values must match
+    pub tags: Vec<(String, Pattern)>,
+}
+
+impl Filter {
+    /// A filter matching every member.
+    pub fn new() -> Filter {
+        Filter {
+            name: None,
+            tags: Vec::new(),
+        }
+    }
+
+    pub fn matches(&self, member: &Member) -> bool {
+        if let Some(ref name) = self.name {
+            if !name.is_match(member.name.as_slice()) {
+                return false;
+            }
+        }
+
+        self.tags.iter().all(|&(ref key, ref pattern)| {
+            match member.tags.get(key) {
+                Some(value) => pattern.is_match(value.as_slice()),
+                None => false,
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+    use std::io::net::ip::{
+        Ipv4Addr,
+        SocketAddr,
+    };
+
+    use regex::Regex;
+    use time;
+
+    use member::{
+        Member,
+        MemberState,
+    };
+
+    use super::{
+        Filter,
+        Pattern,
+    };
+
+    fn member(name: &str, role: Option<&str>) -> Member {
+        let mut tags = BTreeMap::new();
+        if let Some(role) = role {
+            tags.insert("role".to_string(), role.to_string());
+        }
+
+        Member {
+            name: name.to_string(),
+            addr: SocketAddr {
+                ip: Ipv4Addr(127, 0, 0, 1),
+                port: 7201,
+            },
+            state: MemberState::Alive,
+            inc: 0,
+            state_change: time::get_time(),
+            ltime: 0,
+            tags: tags,
+        }
+    }
+
+    fn tag_filter(pattern: Pattern) -> Filter {
+        Filter {
+            name: None,
+            tags: vec![("role".to_string(), pattern)],
+        }
+    }
+
+    #[test]
+    fn empty_filter_matches_everyone() {
+        assert!(Filter::new().matches(&member("web-1", None)));
+    }
+
+    #[test]
+    fn exact_pattern() {
+        let filter = tag_filter(Pattern::Exact("web".to_string()));
+        assert!(filter.matches(&member("a", Some("web"))));
+        assert!(!filter.matches(&member("a", Some("webserver"))));
+        assert!(!filter.matches(&member("a", Some("db"))));
+    }
+
+    #[test]
+    fn prefix_pattern() {
+        let filter = Filter {
+            name: Some(Pattern::Prefix("web-".to_string())),
+            tags: Vec::new(),
+        };
+        assert!(filter.matches(&member("web-1", None)));
+        assert!(filter.matches(&member("web-", None)));
+        assert!(!filter.matches(&member("db-1", None)));
+    }
+
+    #[test]
+    fn regex_pattern() {
+        let filter = tag_filter(Pattern::Regex(Regex::new("^(web|api)$").unwrap()));
+        assert!(filter.matches(&member("a", Some("web"))));
+        assert!(filter.matches(&member("a", Some("api"))));
+        assert!(!filter.matches(&member("a", Some("webapi"))));
+    }
+
+    #[test]
+    fn missing_tag_never_matches() {
+        // Not even a pattern which matches the empty string
+        let filter = tag_filter(Pattern::Prefix(String::new()));
+        assert!(filter.matches(&member("a", Some("db"))));
+        assert!(!filter.matches(&member("a", None)));
+    }
+
+    #[test]
+    fn every_pattern_must_match() {
+        let filter = Filter {
+            name: Some(Pattern::Prefix("web-".to_string())),
+            tags: vec![("role".to_string(), Pattern::Exact("web".to_string()))],
+        };
+        assert!(filter.matches(&member("web-1", Some("web"))));
+        assert!(!filter.matches(&member("web-1", Some("db"))));
+        assert!(!filter.matches(&member("db-1", Some("web"))));
+    }
+}
@@ -1,41 +1,232 @@
-use std::io::net::ip::SocketAddr;
+use std::collections::BTreeMap;
+use std::error::FromError;
+use std::io::net::ip::{
+    Ipv4Addr,
+    Ipv6Addr,
+    SocketAddr,
+};
+use std::io::net::tcp::TcpStream;
 use std::io::net::udp::UdpSocket;
-use std::io::IoResult;
+use std::time::Duration;
+use std::sync::Arc;
+use std::sync::atomic::{
+    AtomicUint,
+    Ordering,
+};
 
-use message::Message;
+use codec;
+use codec::WireFormat;
 
-pub const UDP_MAX_SIZE: uint = 548;
+use config::Config;
+
+use error::{
+    Error,
+    ErosionResult,
+};
+
+use label::{
+    label_overhead,
+    split_label,
+    write_label,
+};
+
+use message::{
+    MAX_REASON_LEN,
+    Message,
+    write_compound,
+};
+
+/// The largest UDP payload. It bounds `packet_size`, and the messages sent
+/// over TCP.
+pub const UDP_MAX_SIZE: uint = 65507;
+
+/// The size of the buffer packets are received into. One byte more than any
+/// UDP payload, so a packet which fills the whole buffer must have been
+/// truncated.
+pub const RECV_BUF_SIZE: uint = UDP_MAX_SIZE + 1;
 
 #[deriving(Clone)]
 pub struct Gossip {
     pub udp: UdpSocket,
+
+    /// The address the socket is actually bound to
+    local_addr: SocketAddr,
+
+    /// The largest packet we send
+    packet_size: uint,
+
+    /// The encoding of the messages we send
+    wire_format: WireFormat,
+
+    /// Whether the socket is an IPv6 one, which may also be dual-stack
+    ipv6: bool,
+
+    /// A socket for IPv4 members, bound next to an IPv6 wildcard socket
+    /// which is IPv6-only
+    udp4: Option<UdpSocket>,
+
+    /// The cluster label every packet starts with, empty for none
+    label: String,
+
+    /// Accept packets without a label, even though we have one
+    allow_unlabeled: bool,
+
+    // Shared by every clone
+    send_failures: Arc<AtomicUint>,
+    truncated_packets: Arc<AtomicUint>,
+    oversized_packets: Arc<AtomicUint>,
+    label_mismatches: Arc<AtomicUint>,
 }
 
 impl Gossip {
-    pub fn new(addr: SocketAddr) -> Result<Gossip, String> {
-        let udp = UdpSocket::bind(addr);
+    /// Bind the UDP socket to `bind_addr`. Binding to the IPv6 wildcard
+    /// falls back to the IPv4 wildcard on hosts without IPv6. Where the OS
+    /// makes IPv6 sockets IPv6-only, a second socket is bound to the IPv4
+    /// wildcard on the same port, so IPv4 members are reached either way.
+    pub fn new(config: &Config) -> ErosionResult<Gossip> {
+        let addr = config.bind_addr;
+        let mut ipv6 = is_ipv6(&addr);
+        let mut udp = UdpSocket::bind(addr);
+        if udp.is_err() && addr.ip == Ipv6Addr(0, 0, 0, 0, 0, 0, 0, 0) {
+            let fallback = SocketAddr {
+                ip: Ipv4Addr(0, 0, 0, 0),
+                port: addr.port,
+            };
+            warn!("Failed to bind {}, falling back to {}", addr, fallback);
+            ipv6 = false;
+            udp = UdpSocket::bind(fallback);
+        }
         if let Err(e) = udp {
-            return Err(format!("Failed to start UDP listener at {}. Err: {}", addr, e));
+            return Err(Error::Bind {
+                addr: addr,
+                err: e,
+            });
+        }
+
+        // The OS picks the port when binding to port 0
+        let mut udp = udp.unwrap();
+        let local_addr = udp.socket_name();
+        if let Err(e) = local_addr {
+            return Err(Error::Bind {
+                addr: addr,
+                err: e,
+            });
+        }
+
+        let local_addr = local_addr.unwrap();
+
+        // Binding the IPv4 wildcard to the same port only succeeds if the
+        // IPv6 socket is IPv6-only. Otherwise it is dual-stack already.
+        let mut udp4 = None;
+        if ipv6 && local_addr.ip == Ipv6Addr(0, 0, 0, 0, 0, 0, 0, 0) {
+            let addr4 = SocketAddr {
+                ip: Ipv4Addr(0, 0, 0, 0),
+                port: local_addr.port,
+            };
+            if let Ok(socket) = UdpSocket::bind(addr4) {
+                info!("{} is IPv6-only, IPv4 members are reached at {}", local_addr, addr4);
+                udp4 = Some(socket);
+            }
         }
 
         Ok(Gossip {
-            udp: udp.unwrap(),
+            udp: udp,
+            local_addr: local_addr,
+            packet_size: config.packet_size,
+            wire_format: config.wire_format,
+            ipv6: ipv6,
+            udp4: udp4,
+            label: config.label.clone(),
+            allow_unlabeled: config.allow_unlabeled,
+            send_failures: Arc::new(AtomicUint::new(0)),
+            truncated_packets: Arc::new(AtomicUint::new(0)),
+            oversized_packets: Arc::new(AtomicUint::new(0)),
+            label_mismatches: Arc::new(AtomicUint::new(0)),
         })
     }
 
-    pub fn recv_from(&mut self) -> IoResult<(Message, SocketAddr)> {
-        let mut buf = [0u8, ..UDP_MAX_SIZE];
-        let result = self.udp.recv_from(&mut buf);
+    /// Receive a single packet into `buf` and decode its message. `buf` is
+    /// reused across calls, it should be `RECV_BUF_SIZE` bytes long so
+    /// packets from members with a larger `packet_size` are not cut off.
+    pub fn recv_from(&mut self, buf: &mut [u8]) -> ErosionResult<(Message, SocketAddr)> {
+        let result = self.udp.recv_from(buf);
         if let Err(e) = result {
-            return Err(e);
+            return Err(Error::Transport(e));
         }
 
         let (count, from) = result.unwrap();
-        let mut buf = buf[..count];
-        match Message::read(&mut buf) {
+        let from = unmap(from);
+        if count >= buf.len() {
+            self.truncated_packets.fetch_add(1, Ordering::Relaxed);
+            error!("Dropped truncated packet from {}", from);
+            return Err(Error::Truncated);
+        }
+        if count > self.packet_size {
+            self.oversized_packets.fetch_add(1, Ordering::Relaxed);
+            warn!("Received a {} bytes packet from {}, larger than our packet size {}",
+                  count, from, self.packet_size);
+        }
+
+        match self.read_packet(buf[..count], from) {
+            Ok(msg) => Ok((msg, from)),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Read a single message sent with `send_tcp` from `stream`. It is
+    /// framed as a big-endian 32 bits length followed by a packet.
+    pub fn read_stream(&mut self,
+                       stream: &mut TcpStream) -> ErosionResult<(Message, SocketAddr)> {
+        let from = match stream.peer_name() {
+            Ok(from) => unmap(from),
+            Err(e) => return Err(Error::Transport(e)),
+        };
+
+        let len = stream.read_be_u32();
+        if let Err(e) = len {
+            return Err(FromError::from_error(e));
+        }
+        let len = len.unwrap() as uint;
+        if len > UDP_MAX_SIZE {
+            return Err(Error::TooLong {
+                field: "Stream message",
+                len: len,
+                max: UDP_MAX_SIZE,
+            });
+        }
+
+        let buf = stream.read_exact(len);
+        if let Err(e) = buf {
+            return Err(FromError::from_error(e));
+        }
+
+        match self.read_packet(buf.unwrap().as_slice(), from) {
+            Ok(msg) => Ok((msg, from)),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Check the label of a packet and decode its message.
+    fn read_packet(&mut self, buf: &[u8], from: SocketAddr) -> ErosionResult<Message> {
+        let (label, buf) = match split_label(buf) {
+            Ok(split) => split,
+            Err(e) => {
+                error!("Failed to decode label from {} => {}", from, e);
+                return Err(e);
+            },
+        };
+        let unlabeled_allowed = label.is_empty() && self.allow_unlabeled;
+        if label != self.label.as_bytes() && !unlabeled_allowed {
+            self.label_mismatches.fetch_add(1, Ordering::Relaxed);
+            let label = String::from_utf8_lossy(label).into_owned();
+            warn!("Dropped packet from {} with label `{}`", from, label);
+            return Err(Error::LabelMismatch(label));
+        }
+
+        match codec::decode(buf) {
             Ok(msg) => {
                 info!("Received message from {} => {}", from, msg);
-                Ok((msg, from))
+                Ok(msg)
             },
             Err(e) => {
                 error!("Failed to decode message from {} => {}", from, e);
@@ -44,41 +235,271 @@ impl Gossip {
         }
     }
 
-    pub fn ping(&mut self, seq: u32, name: String, to: SocketAddr) {
-        let msg = Message::Ping {
+    pub fn ping(&mut self, seq: u32, name: String, to: SocketAddr) -> ErosionResult<()> {
+        self.send_msg(&Message::Ping {
             seq: seq,
             name: name,
-        };
-        let mut buf = Vec::new();
-        if let Err(e) = msg.write(&mut buf) {
-            error!("Failed to encode message. Err: {}", e);
-            return;
+        }, to)
+    }
+
+    pub fn indirect_ping(&mut self, seq: u32, name: String, addr: SocketAddr,
+                         to: SocketAddr) -> ErosionResult<()> {
+        self.send_msg(&Message::IndirectPing {
+            addr: addr,
+            seq: seq,
+            name: name,
+        }, to)
+    }
+
+    pub fn ack (&mut self, seq: u32, payload: Vec<u8>, to: SocketAddr) -> ErosionResult<()> {
+        self.send_msg(&Message::Ack {
+            seq: seq,
+            payload: payload,
+        }, to)
+    }
+
+    pub fn conflict_query(&mut self, seq: u32, name: String,
+                          to: SocketAddr) -> ErosionResult<()> {
+        self.send_msg(&Message::ConflictQuery {
+            seq: seq,
+            name: name,
+        }, to)
+    }
+
+    pub fn join(&mut self, seq: u32, inc: u32, name: String, addr: SocketAddr,
+                tags: BTreeMap<String, String>, ltime: u64,
+                to: SocketAddr) -> ErosionResult<()> {
+        self.send_msg(&Message::Join {
+            seq: seq,
+            inc: inc,
+            name: name,
+            addr: addr,
+            tags: tags,
+            ltime: ltime,
+        }, to)
+    }
+
+    /// Refuse the request `seq`. A `reason` longer than `MAX_REASON_LEN` is
+    /// cut short, so the refusal is still sent.
+    pub fn reject(&mut self, seq: u32, reason: String, to: SocketAddr) -> ErosionResult<()> {
+        let mut reason = reason;
+        if reason.len() > MAX_REASON_LEN {
+            let mut len = MAX_REASON_LEN;
+            while !reason.as_slice().is_char_boundary(len) {
+                len -= 1;
+            }
+            reason.truncate(len);
         }
-        self.send_to(buf.as_slice(),  to);
+
+        self.send_msg(&Message::Reject {
+            seq: seq,
+            reason: reason,
+        }, to)
     }
 
-    pub fn ack (&mut self, seq: u32, to: SocketAddr) {
-        let msg = Message::Ack {
+    pub fn nack(&mut self, seq: u32, to: SocketAddr) -> ErosionResult<()> {
+        self.send_msg(&Message::Nack {
             seq: seq,
-        };
+        }, to)
+    }
+
+    /// Ask `to` to forward the already encoded message `msg` to `addr`.
+    pub fn relay(&mut self, addr: SocketAddr, msg: Vec<u8>,
+                 to: SocketAddr) -> ErosionResult<()> {
+        self.send_msg(&Message::Relay {
+            addr: addr,
+            msg: msg,
+        }, to)
+    }
+
+    /// Send an already encoded message as is.
+    pub fn forward(&mut self, msg: &[u8], to: SocketAddr) -> ErosionResult<()> {
+        self.send_to(msg, to)
+    }
+
+    /// Send a message over a new TCP connection, for messages which don't
+    /// fit into a packet. It is labeled like a packet, see `read_stream`.
+    pub fn send_tcp(&mut self, msg: &Message, to: SocketAddr,
+                    timeout: Duration) -> ErosionResult<()> {
+        let encoded = self.wire_format.codec_for(msg).encode(msg);
+        if let Err(e) = encoded {
+            return self.fail(e);
+        }
+        let encoded = encoded.unwrap();
+
+        let mut buf = Vec::with_capacity(label_overhead(self.label.as_slice()) + encoded.len());
+        if let Err(e) = write_label(&mut buf, self.label.as_slice()) {
+            return self.fail(e);
+        }
+        buf.push_all(encoded.as_slice());
+        if buf.len() > UDP_MAX_SIZE {
+            return self.fail(Error::TooLong {
+                field: "Stream message",
+                len: buf.len(),
+                max: UDP_MAX_SIZE,
+            });
+        }
+
+        info!("Sending message over TCP to {} <= {}", to, msg);
+        let stream = TcpStream::connect_timeout(to, timeout);
+        if let Err(e) = stream {
+            return self.fail(Error::Transport(e));
+        }
+        let mut stream = stream.unwrap();
+        stream.set_write_timeout(Some(timeout.num_milliseconds() as u64));
+
+        if let Err(e) = stream.write_be_u32(buf.len() as u32) {
+            return self.fail(Error::Transport(e));
+        }
+        if let Err(e) = stream.write(buf.as_slice()) {
+            return self.fail(Error::Transport(e));
+        }
+        Ok(())
+    }
+
+    /// Send already encoded messages packed into a single compound message.
+    pub fn compound(&mut self, parts: &[Vec<u8>], to: SocketAddr) -> ErosionResult<()> {
         let mut buf = Vec::new();
-        if let Err(e) = msg.write(&mut buf) {
-            error!("Failed to encode message. Err: {}", e);
-            return;
+        if let Err(e) = write_compound(&mut buf, parts) {
+            return self.fail(e);
         }
-        self.send_to(buf.as_slice(), to);
+        self.send_to(buf.as_slice(), to)
     }
 
-    fn send_to(&mut self, buf: &[u8], to: SocketAddr) {
-        if buf.len() > UDP_MAX_SIZE {
-            error!("Failed to send message. Message is too long ({} bytes).",
-                     buf.len());
-            return;
+    /// Returns the address the socket is bound to.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    /// Returns the address of the IPv4 socket bound next to an IPv6-only
+    /// one, if any. Stream messages have to be accepted there as well.
+    pub fn ipv4_addr(&self) -> Option<SocketAddr> {
+        self.udp4.as_ref().map(|_| SocketAddr {
+            ip: Ipv4Addr(0, 0, 0, 0),
+            port: self.local_addr.port,
+        })
+    }
+
+    /// Returns a gossip receiving on each of our sockets, to be handed to
+    /// a receiving thread each.
+    pub fn receivers(&self) -> Vec<Gossip> {
+        let mut receivers = vec![self.clone()];
+        if let Some(ref udp4) = self.udp4 {
+            let mut receiver = self.clone();
+            receiver.udp = udp4.clone();
+            receiver.ipv6 = false;
+            receivers.push(receiver);
+        }
+        receivers
+    }
+
+    /// Returns the largest packet we send.
+    pub fn packet_size(&self) -> uint {
+        self.packet_size
+    }
+
+    /// Returns the largest message which fits into a packet, after the
+    /// label.
+    pub fn payload_size(&self) -> uint {
+        self.packet_size - label_overhead(self.label.as_slice())
+    }
+
+    /// Returns the number of received packets which were dropped because
+    /// their cluster label didn't match ours.
+    pub fn label_mismatches(&self) -> uint {
+        self.label_mismatches.load(Ordering::Relaxed)
+    }
+
+    /// Returns the number of received packets which were dropped because
+    /// they were truncated.
+    pub fn truncated_packets(&self) -> uint {
+        self.truncated_packets.load(Ordering::Relaxed)
+    }
+
+    /// Returns the number of received packets which were larger than our
+    /// `packet_size`. They are still accepted, but hint at members
+    /// configured with a different packet size.
+    pub fn oversized_packets(&self) -> uint {
+        self.oversized_packets.load(Ordering::Relaxed)
+    }
+
+    /// Returns the number of messages which failed to be encoded or sent.
+    pub fn send_failures(&self) -> uint {
+        self.send_failures.load(Ordering::Relaxed)
+    }
+
+    fn send_msg(&mut self, msg: &Message, to: SocketAddr) -> ErosionResult<()> {
+        match self.wire_format.codec_for(msg).encode(msg) {
+            Ok(buf) => self.send_to(buf.as_slice(), to),
+            Err(e) => self.fail(e),
+        }
+    }
+
+    fn send_to(&mut self, msg: &[u8], to: SocketAddr) -> ErosionResult<()> {
+        let mut buf = Vec::with_capacity(label_overhead(self.label.as_slice()) + msg.len());
+        if let Err(e) = write_label(&mut buf, self.label.as_slice()) {
+            return self.fail(e);
+        }
+        buf.push_all(msg);
+
+        if buf.len() > self.packet_size {
+            return self.fail(Error::TooLong {
+                field: "Packet",
+                len: buf.len(),
+                max: self.packet_size,
+            });
         }
 
         info!("Sending message to {} <= {}", to, buf);
-        if let Err(e) = self.udp.send_to(buf, to) {
-            error!("Failed to send packets to {}. Err: {}", to, e);
+        let result = if !is_ipv6(&to) && self.udp4.is_some() {
+            self.udp4.as_mut().unwrap().send_to(buf.as_slice(), to)
+        } else if self.ipv6 {
+            // A dual-stack socket only takes IPv6 addresses
+            self.udp.send_to(buf.as_slice(), map(to))
+        } else {
+            self.udp.send_to(buf.as_slice(), to)
+        };
+        if let Err(e) = result {
+            return self.fail(Error::Transport(e));
         }
+        Ok(())
+    }
+
+    fn fail(&self, e: Error) -> ErosionResult<()> {
+        self.send_failures.fetch_add(1, Ordering::Relaxed);
+        Err(e)
+    }
+}
+
+fn is_ipv6(addr: &SocketAddr) -> bool {
+    match addr.ip {
+        Ipv6Addr(..) => true,
+        _ => false,
+    }
+}
+
+/// Turn an IPv4 address into an IPv4-mapped IPv6 address.
+fn map(addr: SocketAddr) -> SocketAddr {
+    match addr.ip {
+        Ipv4Addr(a, b, c, d) => SocketAddr {
+            ip: Ipv6Addr(0, 0, 0, 0, 0, 0xffff,
+                         (a as u16 << 8) | b as u16,
+                         (c as u16 << 8) | d as u16),
+            port: addr.port,
+        },
+        _ => addr,
+    }
+}
+
+/// Turn an IPv4-mapped IPv6 address, as reported by a dual-stack socket for
+/// IPv4 peers, back into an IPv4 address, so every member sees the same
+/// address for a peer.
+pub fn unmap(addr: SocketAddr) -> SocketAddr {
+    match addr.ip {
+        Ipv6Addr(0, 0, 0, 0, 0, 0xffff, ab, cd) => SocketAddr {
+            ip: Ipv4Addr((ab >> 8) as u8, ab as u8, (cd >> 8) as u8, cd as u8),
+            port: addr.port,
+        },
+        _ => addr,
     }
 }
@@ -0,0 +1,124 @@
+use std::io::net::ip::{
+    IpAddr,
+    Ipv4Addr,
+    Ipv6Addr,
+};
+use std::mem;
+use std::ptr;
+
+use libc::{
+    c_char,
+    c_int,
+    c_uint,
+    c_void,
+    sockaddr,
+    sockaddr_in,
+    sockaddr_in6,
+    AF_INET,
+    AF_INET6,
+};
+
+// Same values on Linux and the BSDs
+const IFF_UP: c_uint = 0x1;
+const IFF_LOOPBACK: c_uint = 0x8;
+
+#[repr(C)]
+struct ifaddrs {
+    ifa_next: *mut ifaddrs,
+    ifa_name: *mut c_char,
+    ifa_flags: c_uint,
+    ifa_addr: *mut sockaddr,
+    ifa_netmask: *mut sockaddr,
+    ifa_ifu: *mut sockaddr,
+    ifa_data: *mut c_void,
+}
+
+extern {
+    fn getifaddrs(ifap: *mut *mut ifaddrs) -> c_int;
+    fn freeifaddrs(ifa: *mut ifaddrs);
+}
+
+/// Returns the addresses of the interfaces which are up, except loopback.
+pub fn interface_addrs() -> Vec<IpAddr> {
+    let mut addrs = Vec::new();
+
+    unsafe {
+        let mut head: *mut ifaddrs = ptr::null_mut();
+        if getifaddrs(&mut head) != 0 {
+            return addrs;
+        }
+
+        let mut cur = head;
+        while !cur.is_null() {
+            let ifa = &*cur;
+            cur = ifa.ifa_next;
+
+            if ifa.ifa_addr.is_null()
+               || ifa.ifa_flags & IFF_UP == 0
+               || ifa.ifa_flags & IFF_LOOPBACK != 0 {
+                continue;
+            }
+
+            let family = (*ifa.ifa_addr).sa_family as c_int;
+            if family == AF_INET {
+                let sin = ifa.ifa_addr as *const sockaddr_in;
+                let octets: [u8, ..4] = mem::transmute((*sin).sin_addr);
+                addrs.push(Ipv4Addr(octets[0], octets[1], octets[2], octets[3]));
+            } else if family == AF_INET6 {
+                let sin6 = ifa.ifa_addr as *const sockaddr_in6;
+                let octets: [u8, ..16] = mem::transmute((*sin6).sin6_addr);
+                let segment = |i: uint| (octets[2 * i] as u16 << 8) | octets[2 * i + 1] as u16;
+                addrs.push(Ipv6Addr(segment(0), segment(1), segment(2), segment(3),
+                                    segment(4), segment(5), segment(6), segment(7)));
+            }
+        }
+
+        freeifaddrs(head);
+    }
+
+    addrs
+}
+
+/// Returns the first private address of the interfaces. IPv6 unique local
+/// addresses are only considered when `ipv6` is set, and only if there is
+/// no private IPv4 address.
+pub fn private_addr(ipv6: bool) -> Option<IpAddr> {
+    let addrs = interface_addrs();
+
+    let v4 = addrs.iter().find(|ip| is_private(*ip) && !is_ipv6(*ip));
+    if v4.is_some() {
+        return v4.map(|ip| *ip);
+    }
+
+    if ipv6 {
+        return addrs.iter().find(|ip| is_private(*ip)).map(|ip| *ip);
+    }
+
+    None
+}
+
+/// Whether `ip` is in one of the RFC 1918 ranges, the RFC 6598 shared
+/// address space, or the IPv6 unique local range.
+pub fn is_private(ip: &IpAddr) -> bool {
+    match *ip {
+        Ipv4Addr(10, _, _, _) => true,
+        Ipv4Addr(172, b, _, _) => b >= 16 && b < 32,
+        Ipv4Addr(192, 168, _, _) => true,
+        Ipv4Addr(100, b, _, _) => b >= 64 && b < 128,
+        Ipv4Addr(..) => false,
+        Ipv6Addr(a, _, _, _, _, _, _, _) => a & 0xfe00 == 0xfc00,
+    }
+}
+
+/// Whether `ip` is `0.0.0.0` or `::`.
+pub fn is_unspecified(ip: &IpAddr) -> bool {
+    *ip == Ipv4Addr(0, 0, 0, 0) || *ip == Ipv6Addr(0, 0, 0, 0, 0, 0, 0, 0)
+}
+
+/// Whether `ip` is an IPv6 address.
+pub fn is_ipv6(ip: &IpAddr) -> bool {
+    match *ip {
+        Ipv6Addr(..) => true,
+        _ => false,
+    }
+}
@@ -0,0 +1,117 @@
+use std::error::FromError;
+
+use error::{
+    Error,
+    ErosionResult,
+};
+
+/// A reader over input which is already in memory, such as a received
+/// packet. Knowing how many bytes are left, decoders check length prefixes
+/// against it, so a packet can't make them allocate more than it holds.
+pub trait Input: Reader {
+    /// Returns the number of bytes left to read.
+    fn remaining(&self) -> uint;
+}
+
+impl<'a> Input for &'a [u8] {
+    fn remaining(&self) -> uint {
+        self.len()
+    }
+}
+
+/// Read exactly `len` bytes, failing as truncated before anything is
+/// allocated if the input doesn't hold that many.
+pub fn read_bounded<R: Input>(reader: &mut R, len: uint) -> ErosionResult<Vec<u8>> {
+    if len > reader.remaining() {
+        return Err(Error::Truncated);
+    }
+
+    match reader.read_exact(len) {
+        Ok(bytes) => Ok(bytes),
+        Err(e) => Err(FromError::from_error(e)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cmp;
+    use std::io::IoResult;
+
+    use error::Error;
+    use message::Message;
+    use msgpack::read_value;
+
+    use super::Input;
+
+    /// Reads from a buffer, and remembers the largest `read_exact`, which
+    /// allocates its whole length up front.
+    struct Recording<'a> {
+        buf: &'a [u8],
+        largest: uint,
+    }
+
+    impl<'a> Reader for Recording<'a> {
+        fn read(&mut self, buf: &mut [u8]) -> IoResult<uint> {
+            self.buf.read(buf)
+        }
+
+        fn read_exact(&mut self, len: uint) -> IoResult<Vec<u8>> {
+            self.largest = cmp::max(self.largest, len);
+            self.buf.read_exact(len)
+        }
+    }
+
+    impl<'a> Input for Recording<'a> {
+        fn remaining(&self) -> uint {
+            self.buf.len()
+        }
+    }
+
+    /// Asserts that `buf` fails to decode as truncated, without reading, and
+    /// so allocating, more than it holds.
+    fn assert_bounded(buf: &[u8], decode: |&mut Recording| -> bool) {
+        let mut reader = Recording {
+            buf: buf,
+            largest: 0,
+        };
+        assert!(!decode(&mut reader), "{} decoded", buf);
+        assert!(reader.largest <= buf.len(),
+                "{} bytes read from {} bytes", reader.largest, buf.len());
+    }
+
+    #[test]
+    fn msgpack_length_headers_are_checked_against_the_input() {
+        // bin 32, str 32, bin 16 and str 16 of the largest length
+        let headers: [&[u8], ..4] = [
+            &[0xc6, 0xff, 0xff, 0xff, 0xff, 0x00],
+            &[0xdb, 0xff, 0xff, 0xff, 0xff, 0x00],
+            &[0xc5, 0xff, 0xff, 0x00],
+            &[0xda, 0xff, 0xff, 0x00],
+        ];
+        for buf in headers.iter() {
+            assert_bounded(*buf, |reader| read_value(reader).is_ok());
+        }
+    }
+
+    #[test]
+    fn legacy_length_prefixes_are_checked_against_the_input() {
+        // A ping with a name of the largest length, and a compound of one
+        // part of the largest length
+        let headers: [&[u8], ..2] = [
+            &[0x00, 0x00, 0x00, 0x00, 0x01, 0x80, 0x04, 0x00],
+            &[0x07, 0x01, 0xff, 0xff, 0x00],
+        ];
+        for buf in headers.iter() {
+            assert_bounded(*buf, |reader| Message::read(reader).is_ok());
+        }
+    }
+
+    #[test]
+    fn truncated_input_fails_as_truncated() {
+        let mut buf: &[u8] = &[0xc4, 0x10, 0x00];
+        match read_value(&mut buf) {
+            Err(Error::Truncated) => (),
+            result => panic!("Unexpected {}", result),
+        }
+    }
+}
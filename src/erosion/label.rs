@@ -0,0 +1,64 @@
+use std::error::FromError;
+use std::io::Writer;
+
+use error::{
+    Error,
+    ErosionResult,
+};
+
+/// Starts the header of a labeled packet. Legacy messages start with their
+/// type, which is below 0x80, and MessagePack messages with a map, which is
+/// below 0x90, so it is never mistaken for either.
+pub const LABEL_MARKER: u8 = 0xf4;
+
+/// The longest cluster label, in bytes.
+pub const MAX_LABEL_LEN: uint = 255;
+
+/// The number of bytes the header of `label` takes.
+pub fn label_overhead(label: &str) -> uint {
+    if label.is_empty() { 0 } else { 2 + label.len() }
+}
+
+/// Write the header of a labeled packet: the marker, the length of the
+/// label, then the label. Nothing is written for an empty label.
+pub fn write_label<W: Writer>(writer: &mut W, label: &str) -> ErosionResult<()> {
+    if label.is_empty() {
+        return Ok(());
+    }
+
+    if label.len() > MAX_LABEL_LEN {
+        return Err(Error::TooLong {
+            field: "Label",
+            len: label.len(),
+            max: MAX_LABEL_LEN,
+        });
+    }
+
+    if let Err(e) = writer.write_u8(LABEL_MARKER) {
+        return Err(FromError::from_error(e));
+    }
+    if let Err(e) = writer.write_u8(label.len() as u8) {
+        return Err(FromError::from_error(e));
+    }
+    if let Err(e) = writer.write_str(label) {
+        return Err(FromError::from_error(e));
+    }
+    Ok(())
+}
+
+/// Split a packet into its label, empty if unlabeled, and the message.
+pub fn split_label<'a>(buf: &'a [u8]) -> ErosionResult<(&'a [u8], &'a [u8])> {
+    if buf.is_empty() || buf[0] != LABEL_MARKER {
+        return Ok((buf[..0], buf));
+    }
+
+    if buf.len() < 2 {
+        return Err(Error::Truncated);
+    }
+    let len = buf[1] as uint;
+    if buf.len() < 2 + len {
+        return Err(Error::Truncated);
+    }
+
+    Ok((buf[2..2 + len], buf[2 + len..]))
+}
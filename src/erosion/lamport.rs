@@ -0,0 +1,178 @@
+use std::num::Int;
+use std::sync::Mutex;
+
+/// A Lamport clock, which orders events across the cluster: an event
+/// happening after another one, on any node, has a higher time.
+pub struct LamportClock {
+    time: Mutex<u64>,
+}
+
+impl LamportClock {
+    pub fn new() -> LamportClock {
+        LamportClock {
+            time: Mutex::new(0),
+        }
+    }
+
+    /// Returns the current time.
+    pub fn time(&self) -> u64 {
+        *self.time.lock()
+    }
+
+    /// Advance the clock for a local event, and return its time.
+    pub fn increment(&self) -> u64 {
+        let mut time = self.time.lock();
+        (*time) = time.saturating_add(1);
+        *time
+    }
+
+    /// Catch up with the time of an event received from another node, so
+    /// our next events happen after it. The time comes from the network, so
+    /// the clock stops at `u64::MAX` rather than wrapping around to 0.
+    pub fn witness(&self, ltime: u64) {
+        let mut time = self.time.lock();
+        if ltime >= *time {
+            (*time) = ltime.saturating_add(1);
+        }
+    }
+}
+
+/// Remembers the events of the last `len` Lamport times, to drop events
+/// received several times through gossip. Events older than that can't be
+/// told apart from duplicates and are dropped too.
+pub struct RecentEvents<T> {
+    /// The events of a Lamport time are kept at `ltime % len`
+    buckets: Vec<Option<(u64, Vec<T>)>>,
+}
+
+impl<T: PartialEq> RecentEvents<T> {
+    pub fn new(len: uint) -> RecentEvents<T> {
+        RecentEvents {
+            buckets: range(0, len).map(|_| None).collect(),
+        }
+    }
+
+    /// Remember `event` which happened at `ltime`, when our clock is at
+    /// `now`. Returns `false` if it is a duplicate, or too old to tell.
+    pub fn witness(&mut self, ltime: u64, now: u64, event: T) -> bool {
+        // Only the last `len` times, up to `now`, have a bucket
+        let len = self.buckets.len() as u64;
+        if len == 0 || (now >= len && ltime <= now - len) {
+            return false;
+        }
+
+        let bucket = &mut self.buckets[(ltime % len) as uint];
+        if let Some((time, ref mut events)) = *bucket {
+            // The bucket was taken over by a later time
+            if time > ltime {
+                return false;
+            }
+            if time == ltime {
+                if events.contains(&event) {
+                    return false;
+                }
+                events.push(event);
+                return true;
+            }
+        }
+
+        // The bucket is empty or holds older events
+        (*bucket) = Some((ltime, vec![event]));
+        true
+    }
+
+    /// Returns whether any of the remembered events matches `f`.
+    pub fn any(&self, f: |&T| -> bool) -> bool {
+        for bucket in self.buckets.iter() {
+            if let &Some((_, ref events)) = bucket {
+                for event in events.iter() {
+                    if f(event) {
+                        return true;
+                    }
+                }
+            }
+        }
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::u64;
+
+    use super::{
+        LamportClock,
+        RecentEvents,
+    };
+
+    #[test]
+    fn clock_witnesses_later_times_only() {
+        let clock = LamportClock::new();
+        clock.witness(5);
+        assert_eq!(clock.time(), 6);
+        clock.witness(3);
+        assert_eq!(clock.time(), 6);
+        assert_eq!(clock.increment(), 7);
+    }
+
+    #[test]
+    fn clock_saturates() {
+        let clock = LamportClock::new();
+        clock.witness(u64::MAX);
+        assert_eq!(clock.time(), u64::MAX);
+        assert_eq!(clock.increment(), u64::MAX);
+    }
+
+    #[test]
+    fn duplicate_is_dropped() {
+        let mut events = RecentEvents::new(4);
+        assert!(events.witness(1, 1, "a"));
+        assert!(events.witness(1, 1, "b"));
+        assert!(!events.witness(1, 1, "a"));
+        assert!(events.witness(2, 2, "a"));
+        assert!(!events.witness(2, 2, "a"));
+    }
+
+    #[test]
+    fn too_old_is_dropped() {
+        let mut events = RecentEvents::new(4);
+        assert!(!events.witness(6, 10, "a"));
+        assert!(events.witness(7, 10, "a"));
+    }
+
+    #[test]
+    fn bucket_is_reused_for_a_later_time() {
+        let mut events = RecentEvents::new(4);
+        assert!(events.witness(1, 1, "a"));
+
+        // Time 5 takes over the bucket of time 1, which is forgotten
+        assert!(events.witness(5, 5, "b"));
+        assert!(!events.witness(5, 5, "b"));
+        assert!(events.witness(5, 5, "a"));
+    }
+
+    #[test]
+    fn bucket_of_a_later_time_is_kept() {
+        let mut events = RecentEvents::new(4);
+        assert!(events.witness(5, 5, "a"));
+
+        // Time 1 would take the same bucket, had time 5 not taken it
+        assert!(!events.witness(1, 2, "b"));
+        assert!(!events.witness(5, 5, "a"));
+    }
+
+    #[test]
+    fn no_buckets_drops_everything() {
+        let mut events = RecentEvents::new(0);
+        assert!(!events.witness(1, 1, "a"));
+    }
+
+    #[test]
+    fn any_finds_remembered_events() {
+        let mut events = RecentEvents::new(4);
+        events.witness(1, 1, "a");
+        events.witness(2, 2, "b");
+        assert!(events.any(|&event| event == "b"));
+        assert!(!events.any(|&event| event == "c"));
+    }
+}
@@ -8,9 +8,29 @@
 
 #[phase(plugin, link)]
 extern crate log;
+extern crate libc;
+extern crate regex;
+extern crate time;
 
+pub mod ack;
+pub mod awareness;
+pub mod broadcast;
+pub mod codec;
 pub mod config;
+pub mod coordinate;
+pub mod delegate;
+pub mod error;
+pub mod event;
+pub mod filter;
+pub mod input;
 pub mod member;
+pub mod member_store;
 pub mod membership;
 pub mod message;
+pub mod msgpack;
+pub mod query;
+pub mod rtt;
 pub mod gossip;
+pub mod ifaddr;
+pub mod label;
+pub mod lamport;
@@ -1,5 +1,8 @@
+use std::collections::BTreeMap;
 use std::io::net::ip::SocketAddr;
 
+use time::Timespec;
+
 #[deriving(Clone, Show)]
 pub struct Member {
     pub name: String,
@@ -8,6 +11,15 @@ pub struct Member {
 
     /// Last known incarnation number
     pub inc: u32,
+
+    /// Time of the last state change
+    pub state_change: Timespec,
+
+    /// Lamport time of the last join, leave or death
+    pub ltime: u64,
+
+    /// Set by the member itself, e.g. its role or version
+    pub tags: BTreeMap<String, String>,
 }
 
 #[deriving(Copy, PartialEq, Clone, Show)]
@@ -15,4 +27,5 @@ pub enum MemberState {
     Alive,
     Suspect,
     Dead,
+    Left,
 }
@@ -0,0 +1,318 @@
+use std::collections::HashMap;
+use std::rand::{
+    task_rng,
+    Rng,
+};
+use std::sync::{
+    Arc,
+    Mutex,
+    RWLock,
+};
+
+use member::Member;
+
+/// The order members are probed in. Probing walks `names` from `index`, and
+/// a new round starts with a shuffled list once `index` wraps around.
+struct ProbeOrder {
+    names: Vec<String>,
+    index: uint,
+}
+
+/// The member table.
+///
+/// Members are indexed by name, and every member has its own lock, so
+/// updating one member doesn't block lookups of the others. The probe order
+/// lives behind a separate lock, so probing doesn't contend with message
+/// handling or snapshots.
+pub struct MemberStore {
+    members: RWLock<HashMap<String, Arc<RWLock<Member>>>>,
+
+    probe_order: Mutex<ProbeOrder>,
+}
+
+impl MemberStore {
+    pub fn new() -> MemberStore {
+        MemberStore {
+            members: RWLock::new(HashMap::new()),
+            probe_order: Mutex::new(ProbeOrder {
+                names: Vec::new(),
+                index: 0,
+            }),
+        }
+    }
+
+    pub fn len(&self) -> uint {
+        self.members.read().len()
+    }
+
+    pub fn get(&self, name: &str) -> Option<Arc<RWLock<Member>>> {
+        self.members.read().get(name).map(|member| member.clone())
+    }
+
+    /// Add a new member. Returns `false` if a member with the same name is
+    /// already known.
+    ///
+    /// The member is inserted at a random position among the members which
+    /// are not probed yet in the current round, so every member is still
+    /// probed exactly once per round.
+    pub fn insert(&self, member: Member) -> bool {
+        let name = member.name.clone();
+        {
+            let mut members = self.members.write();
+            if members.contains_key(&name) {
+                return false;
+            }
+            members.insert(name.clone(), Arc::new(RWLock::new(member)));
+        }
+
+        let mut probe_order = self.probe_order.lock();
+        let len = probe_order.names.len();
+        let offset = if probe_order.index < len {
+            task_rng().gen_range(probe_order.index, len + 1)
+        } else {
+            len
+        };
+        probe_order.names.insert(offset, name);
+        true
+    }
+
+    pub fn remove(&self, name: &str) {
+        if self.members.write().remove(name).is_none() {
+            return;
+        }
+        self.remove_from_probe_order(name);
+    }
+
+    /// Remove the member `name` if `filter` returns `true` for it, and
+    /// return it. The member is checked under its write lock while the
+    /// table is locked, so it can't change between the check and the
+    /// removal.
+    pub fn remove_if(&self, name: &str, filter: |&Member| -> bool) -> Option<Member> {
+        let removed = {
+            let mut members = self.members.write();
+            let removed = match members.get(name) {
+                Some(member) => {
+                    let member = member.write();
+                    if !filter(&*member) {
+                        return None;
+                    }
+                    member.clone()
+                },
+                None => return None,
+            };
+            members.remove(name);
+            removed
+        };
+
+        self.remove_from_probe_order(name);
+        Some(removed)
+    }
+
+    fn remove_from_probe_order(&self, name: &str) {
+        let mut probe_order = self.probe_order.lock();
+        if let Some(position) = probe_order.names.iter().position(|n| n.as_slice() == name) {
+            probe_order.names.remove(position);
+            if position < probe_order.index {
+                probe_order.index -= 1;
+            }
+        }
+    }
+
+    /// Returns a copy of every member.
+    pub fn snapshot(&self) -> Vec<Member> {
+        self.members.read().values().map(|member| member.read().clone()).collect()
+    }
+
+    /// Returns a copy of every member for which `filter` returns `true`.
+    pub fn select(&self, filter: |&Member| -> bool) -> Vec<Member> {
+        let mut selected = Vec::new();
+        for member in self.members.read().values() {
+            let member = member.read();
+            if filter(&*member) {
+                selected.push(member.clone());
+            }
+        }
+        selected
+    }
+
+    /// Returns up to `count` random members for which `filter` returns
+    /// `true`, without copying the whole table.
+    pub fn random_members(&self, count: uint, filter: |&Member| -> bool) -> Vec<Member> {
+        let mut rng = task_rng();
+        let mut selected = Vec::with_capacity(count);
+        let mut seen = 0u;
+
+        // Reservoir sampling
+        for member in self.members.read().values() {
+            let member = member.read();
+            if !filter(&*member) {
+                continue;
+            }
+
+            seen += 1;
+            if selected.len() < count {
+                selected.push(member.clone());
+            } else {
+                let index = rng.gen_range(0, seen);
+                if index < count {
+                    selected[index] = member.clone();
+                }
+            }
+        }
+
+        selected
+    }
+
+    /// Returns the name of the next member to probe in the current round,
+    /// or `None` when the round is over.
+    pub fn next_to_probe(&self) -> Option<String> {
+        let mut probe_order = self.probe_order.lock();
+        if probe_order.index >= probe_order.names.len() {
+            return None;
+        }
+
+        let name = probe_order.names[probe_order.index].clone();
+        probe_order.index += 1;
+        Some(name)
+    }
+
+    /// Start a new probe round in a new random order.
+    pub fn reset_probe_order(&self) {
+        let mut probe_order = self.probe_order.lock();
+        task_rng().shuffle(probe_order.names.as_mut_slice());
+        probe_order.index = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+    use std::io::net::ip::{
+        Ipv4Addr,
+        SocketAddr,
+    };
+
+    use time;
+
+    use member::{
+        Member,
+        MemberState,
+    };
+
+    use super::MemberStore;
+
+    fn member(name: &str) -> Member {
+        Member {
+            name: name.to_string(),
+            addr: SocketAddr {
+                ip: Ipv4Addr(127, 0, 0, 1),
+                port: 7201,
+            },
+            state: MemberState::Alive,
+            inc: 0,
+            state_change: time::get_time(),
+            ltime: 0,
+            tags: BTreeMap::new(),
+        }
+    }
+
+    fn names(count: uint) -> Vec<String> {
+        range(0, count).map(|i| format!("node-{}", i)).collect()
+    }
+
+    /// Returns the members probed until the round is over, sorted.
+    fn probe_round(store: &MemberStore) -> Vec<String> {
+        let mut probed = Vec::new();
+        while let Some(name) = store.next_to_probe() {
+            probed.push(name);
+        }
+        probed.sort();
+        probed
+    }
+
+    #[test]
+    fn every_member_is_probed_once_per_round() {
+        let store = MemberStore::new();
+        for name in names(10).iter() {
+            assert!(store.insert(member(name.as_slice())));
+        }
+
+        let mut expected = names(10);
+        expected.sort();
+        for _ in range(0u, 5) {
+            assert_eq!(probe_round(&store), expected);
+            assert_eq!(store.next_to_probe(), None);
+            store.reset_probe_order();
+        }
+    }
+
+    #[test]
+    fn member_inserted_during_a_round_is_probed_in_it() {
+        let store = MemberStore::new();
+        for name in names(10).iter() {
+            store.insert(member(name.as_slice()));
+        }
+
+        let mut probed = Vec::new();
+        for _ in range(0u, 4) {
+            probed.push(store.next_to_probe().unwrap());
+        }
+        assert!(store.insert(member("late")));
+        probed.extend(probe_round(&store).into_iter());
+        probed.sort();
+
+        let mut expected = names(10);
+        expected.push("late".to_string());
+        expected.sort();
+        assert_eq!(probed, expected);
+    }
+
+    #[test]
+    fn removing_a_probed_member_skips_nobody() {
+        let store = MemberStore::new();
+        for name in names(10).iter() {
+            store.insert(member(name.as_slice()));
+        }
+
+        let mut probed = Vec::new();
+        for _ in range(0u, 4) {
+            probed.push(store.next_to_probe().unwrap());
+        }
+        let removed = probed[0].clone();
+        store.remove(removed.as_slice());
+        probed.retain(|name| *name != removed);
+        probed.extend(probe_round(&store).into_iter());
+        probed.sort();
+
+        let mut expected: Vec<String> = names(10).into_iter().filter(|name| {
+            *name != removed
+        }).collect();
+        expected.sort();
+        assert_eq!(probed, expected);
+    }
+
+    #[test]
+    fn remove_if_checks_the_current_member() {
+        let store = MemberStore::new();
+        store.insert(member("a"));
+
+        let dead = |member: &Member| member.state == MemberState::Dead;
+        assert!(store.remove_if("a", dead).is_none());
+        assert_eq!(store.len(), 1);
+
+        store.get("a").unwrap().write().state = MemberState::Dead;
+        let removed = store.remove_if("a", dead);
+        assert_eq!(removed.map(|member| member.name), Some("a".to_string()));
+        assert_eq!(store.len(), 0);
+        assert_eq!(store.next_to_probe(), None);
+    }
+
+    #[test]
+    fn duplicate_member_is_not_inserted() {
+        let store = MemberStore::new();
+        assert!(store.insert(member("a")));
+        assert!(!store.insert(member("a")));
+        assert_eq!(store.len(), 1);
+        assert_eq!(probe_round(&store), vec!["a".to_string()]);
+    }
+}
@@ -1,70 +1,287 @@
-use std::collections::HashMap;
+use std::cmp;
+use std::collections::{
+    BTreeMap,
+    HashMap,
+};
+use std::io::{
+    Acceptor,
+    Listener,
+};
+use std::io::net::tcp::{
+    TcpAcceptor,
+    TcpListener,
+};
 use std::io::timer::Timer;
-use std::io::net::ip::SocketAddr;
-use std::rand::{
-    task_rng,
-    Rng,
+use std::io::net::ip::{
+    Ipv4Addr,
+    SocketAddr,
 };
 use std::sync::{
     Arc,
-    RWLock,
     Mutex,
 };
+use std::sync::atomic::{
+    AtomicBool,
+    Ordering,
+};
+use std::num::{
+    Float,
+    Int,
+};
 use std::thread::Thread;
 use std::time::Duration;
+use std::u32;
+use std::u64;
+
+use time;
+use time::Timespec;
 
 use member::{
     Member,
     MemberState,
 };
+use member_store::MemberStore;
+
+use ack::{
+    AckHandler,
+    AckRouter,
+};
+use awareness::Awareness;
+
+use coordinate::{
+    Coordinate,
+    Coordinates,
+};
+
+use filter::Filter;
+
+use delegate::{
+    AliveDelegate,
+    ConflictDelegate,
+    MergeDelegate,
+    PingDelegate,
+    MAX_PING_PAYLOAD_LEN,
+};
+
+use broadcast::TransmitLimitedQueue;
+
+use codec;
+use codec::WireFormat;
+
+use gossip::{
+    Gossip,
+    RECV_BUF_SIZE,
+    UDP_MAX_SIZE,
+};
+
+use ifaddr;
+
+use label::{
+    label_overhead,
+    MAX_LABEL_LEN,
+};
+
+use rtt::{
+    Latencies,
+    RttSummary,
+};
+
+use msgpack::{
+    Value,
+    read_value,
+    write_value,
+};
 
-use gossip::Gossip;
+use message::{
+    Message,
+    COMPOUND_HEADER_OVERHEAD,
+    COMPOUND_PART_OVERHEAD,
+    MAX_NAME_LEN,
+    MAX_PAYLOAD_LEN,
+    MAX_QUERY_PAYLOAD_LEN,
+};
+
+use query::{
+    Queries,
+    QueryResponses,
+};
+
+use event::{
+    MemberEventCoalescer,
+    MemberEventKind,
+    RateLimiter,
+    UserEventCoalescer,
+    notify_member_event,
+};
 
-use message::Message;
+use lamport::{
+    LamportClock,
+    RecentEvents,
+};
 
 use config::Config;
 
+use error::{
+    Error,
+    ErosionResult,
+};
+
 
 pub struct Membership {
     started: bool,
 
+    /// Set to stop receiving messages, if we are. Receiving starts before
+    /// starting while joining, and stops again if the join fails.
+    listening: Option<Arc<AtomicBool>>,
+
     gossip: Gossip,
 
+    /// Listen on the same port as `gossip`, for messages which don't fit
+    /// into a packet. There is one per socket of `gossip`.
+    tcp: Vec<TcpAcceptor>,
+
     meta: Arc<MembershipMeta>,
 
     message_sender: Arc<Mutex<Option<Sender<(Message, SocketAddr)>>>>,
-
-    /// Local incarnation number
-    inc: u32,
 }
 
 impl Membership {
     /// Create the network listeners
-    pub fn bind(config: Config) -> Result<Membership, String> {
-        let gossip = Gossip::new(config.bind_addr);
-        if let Err(e) = gossip {
+    pub fn bind(config: Config) -> ErosionResult<Membership> {
+        if config.name.len() > MAX_NAME_LEN {
+            return Err(Error::TooLong {
+                field: "Name",
+                len: config.name.len(),
+                max: MAX_NAME_LEN,
+            });
+        }
+
+        if config.label.len() > MAX_LABEL_LEN {
+            return Err(Error::TooLong {
+                field: "Label",
+                len: config.label.len(),
+                max: MAX_LABEL_LEN,
+            });
+        }
+
+        if config.packet_size > UDP_MAX_SIZE {
+            return Err(Error::TooLong {
+                field: "Packet size",
+                len: config.packet_size,
+                max: UDP_MAX_SIZE,
+            });
+        }
+
+        // The label and a compound message with a single part must fit
+        let min_packet_size = label_overhead(config.label.as_slice())
+                              + COMPOUND_HEADER_OVERHEAD + COMPOUND_PART_OVERHEAD;
+        if config.packet_size <= min_packet_size {
+            return Err(Error::InvalidConfig(format!(
+                "packet_size must be larger than {} bytes", min_packet_size)));
+        }
+
+        // Indirect pings wait for what is left of the probe interval after
+        // the probe timeout. The awareness multiplier scales both alike, and
+        // the tuned timeout stays below the interval too.
+        if !config.probe_interval.is_zero()
+           && (config.probe_timeout <= Duration::zero()
+               || config.probe_timeout >= config.probe_interval) {
+            return Err(Error::InvalidConfig(
+                "probe_timeout must be positive and shorter than probe_interval".to_string()));
+        }
+
+        // Coalescing waits for these, and a batch must be allowed to settle
+        // before it is flushed
+        let periods = [config.member_event_quiet_period, config.member_event_max_delay,
+                       config.coalesce_user_events];
+        if periods.iter().any(|period| *period < Duration::zero()) {
+            return Err(Error::InvalidConfig(
+                "Event coalescing periods must not be negative".to_string()));
+        }
+        if !config.member_event_quiet_period.is_zero()
+           && config.member_event_max_delay < config.member_event_quiet_period {
+            return Err(Error::InvalidConfig(
+                "member_event_max_delay must be at least member_event_quiet_period".to_string()));
+        }
+
+        let transport = bind_transport(&config);
+        if let Err(e) = transport {
+            return Err(e);
+        }
+        let (gossip, tcp) = transport.unwrap();
+
+        let advertise_addr = advertise_addr(&config, gossip.local_addr());
+        if let Err(e) = advertise_addr {
+            return Err(e);
+        }
+
+        // The legacy layout of the Alive message only has room for IPv4
+        if config.wire_format == WireFormat::Legacy
+           && ifaddr::is_ipv6(&advertise_addr.as_ref().unwrap().ip) {
+            return Err(Error::InvalidConfig(format!(
+                "Advertising the IPv6 address {} needs the MessagePack wire format",
+                advertise_addr.unwrap())));
+        }
+
+        let config_tags = config.tags.clone();
+        if let Err(e) = check_tags(&config, advertise_addr.unwrap(), gossip.payload_size(),
+                                   &config_tags) {
             return Err(e);
         }
 
-        let members = Vec::new();
+        let event_coalescer = match config.event_delegate {
+            Some(ref delegate) if !config.coalesce_user_events.is_zero() => {
+                Some(UserEventCoalescer::start(config.coalesce_user_events, delegate.clone()))
+            },
+            _ => None,
+        };
+        let member_coalescer = match config.event_delegate {
+            Some(ref delegate) if !config.member_event_quiet_period.is_zero() => {
+                Some(MemberEventCoalescer::start(config.member_event_quiet_period,
+                                                 config.member_event_max_delay,
+                                                 delegate.clone()))
+            },
+            _ => None,
+        };
 
         Ok(Membership {
             started: false,
+            listening: None,
+
+            gossip: gossip,
 
-            gossip: gossip.unwrap(),
+            tcp: tcp,
 
             meta: Arc::new(MembershipMeta {
+                awareness: Awareness::new(config.awareness_max_multiplier),
+                broadcasts: Mutex::new(TransmitLimitedQueue::new(config.retransmit_mult)),
+                queries: Queries::new(config.query_buffer_len),
+                recent_events: Mutex::new(RecentEvents::new(config.event_buffer_len)),
+                event_rate: RateLimiter::new(config.user_event_rate_limit, Duration::seconds(1)),
                 config: config,
-                members: RWLock::new(members),
-                ack_senders: Mutex::new(HashMap::new()),
+                advertise_addr: advertise_addr.unwrap(),
+                members: MemberStore::new(),
+                tombstones: Mutex::new(HashMap::new()),
+                acks: AckRouter::start(),
+                latencies: Latencies::new(),
+                coordinates: Coordinates::new(),
 
                 seq: Mutex::new(0),
-                probe_index: Mutex::new(0),
+                inc: Mutex::new(0),
+                tags: Mutex::new(config_tags),
+
+                clock: LamportClock::new(),
+                event_clock: LamportClock::new(),
+                query_clock: LamportClock::new(),
+                event_min_ltime: Mutex::new(0),
+                event_coalescer: event_coalescer,
+                member_coalescer: member_coalescer,
+
+                resolving_conflict: AtomicBool::new(false),
+                shutdown: AtomicBool::new(false),
             }),
 
             message_sender: Arc::new(Mutex::new(None)),
-
-            inc: 0,
         })
     }
 
@@ -73,31 +290,327 @@ impl Membership {
             return;
         }
 
+        let name = self.meta.config.name.clone();
+        let addr = self.meta.advertise_addr;
+        let inc = *self.meta.inc.lock();
+        let tags = self.meta.tags.lock().clone();
+        let ltime = self.meta.clock.increment();
+        let _ = self.meta.alive_node(inc, name, addr, tags, ltime, AliveOrigin::Local);
+
         self.start_gossip_listening();
         self.start_probing();
+        self.start_gossiping();
 
         self.started = true;
     }
 
-    /// Join a existing cluster
-    pub fn join(&mut self, name: String, addr: SocketAddr) {
+    /// Stop probing, gossiping and answering other members. It doesn't tell
+    /// the cluster, so the other members will declare the local node dead.
+    pub fn shutdown(&self) {
+        self.meta.stop();
+    }
+
+    /// Whether the local node has been shut down, by `shutdown` or because
+    /// it lost a name conflict.
+    pub fn is_shutdown(&self) -> bool {
+        self.meta.is_shutdown()
+    }
+
+    /// Returns the address the UDP socket and TCP listener are bound to,
+    /// with the port picked by the OS if `bind_addr` had port 0.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.gossip.local_addr()
+    }
+
+    /// Returns the address other members reach us at.
+    pub fn advertise_addr(&self) -> SocketAddr {
+        self.meta.advertise_addr
+    }
+
+    /// Returns a snapshot of the members known to the local node.
+    pub fn members(&self) -> Vec<Member> {
+        self.meta.members.snapshot()
+    }
+
+    /// Returns the members matched by `filter`.
+    pub fn members_with(&self, filter: &Filter) -> Vec<Member> {
+        self.meta.members.select(|member| filter.matches(member))
+    }
+
+    /// Replace the tags of the local node and gossip them to the cluster
+    /// with a new incarnation. Each node only sets its own tags, so they
+    /// never conflict. Fails if there are more than `MAX_TAGS` tags, or
+    /// they are too large for our Alive message to fit into a packet and
+    /// into the answer to a join.
+    pub fn set_tags(&self, tags: BTreeMap<String, String>) -> ErosionResult<()> {
+        if let Err(e) = check_tags(&self.meta.config, self.meta.advertise_addr,
+                                   self.gossip.payload_size(), &tags) {
+            return Err(e);
+        }
+
+        (*self.meta.tags.lock()) = tags.clone();
+        if !self.started {
+            return Ok(());
+        }
+
+        let inc = {
+            let mut inc = self.meta.inc.lock();
+            (*inc) += 1;
+            *inc
+        };
+        let name = self.meta.config.name.clone();
+        let addr = self.meta.advertise_addr;
+        let ltime = self.meta.clock.increment();
+        let _ = self.meta.alive_node(inc, name, addr, tags, ltime, AliveOrigin::Local);
+        Ok(())
+    }
+
+    /// Returns the number of messages which failed to be encoded or sent.
+    pub fn send_failures(&self) -> uint {
+        self.gossip.send_failures()
+    }
+
+    /// Returns the number of received packets which were dropped because
+    /// they were truncated.
+    pub fn truncated_packets(&self) -> uint {
+        self.gossip.truncated_packets()
+    }
+
+    /// Returns the number of received packets which were larger than our
+    /// `packet_size`, but were accepted anyway.
+    pub fn oversized_packets(&self) -> uint {
+        self.gossip.oversized_packets()
+    }
+
+    /// Returns the number of received packets which were dropped because
+    /// their cluster label didn't match ours.
+    pub fn label_mismatches(&self) -> uint {
+        self.gossip.label_mismatches()
+    }
+
+    /// Returns the round-trip time statistics of the member `name`, measured
+    /// by probing it.
+    pub fn rtt(&self, name: &str) -> Option<RttSummary> {
+        self.meta.latencies.summary(name)
+    }
+
+    /// Returns the network coordinate of the member `name`, as last reported
+    /// in its acks, or the local coordinate for the local node.
+    pub fn coordinate(&self, name: &str) -> Option<Coordinate> {
+        if !self.meta.config.enable_coordinates {
+            return None;
+        }
+
+        if name == self.meta.config.name.as_slice() {
+            return Some(self.meta.coordinates.local());
+        }
+        self.meta.coordinates.get(name)
+    }
+
+    /// Estimate the round-trip time between the members `a` and `b` from
+    /// their network coordinates.
+    pub fn estimate_rtt(&self, a: &str, b: &str) -> Option<Duration> {
+        match (self.coordinate(a), self.coordinate(b)) {
+            (Some(a), Some(b)) => Some(a.distance_to(&b)),
+            _ => None,
+        }
+    }
+
+    /// Ask the members matched by `filter` the query `name`, with
+    /// `payload`. It is disseminated by gossip, and every matched member
+    /// acks it and answers with the response of its `QueryDelegate`, over
+    /// UDP, or TCP if the response doesn't fit into a packet. Answers
+    /// arriving after `timeout` are dropped.
+    pub fn query(&self, name: String, payload: Vec<u8>, filter: Filter,
+                 timeout: Duration) -> ErosionResult<QueryResponses> {
+        if payload.len() > MAX_QUERY_PAYLOAD_LEN {
+            return Err(Error::TooLong {
+                field: "Payload",
+                len: payload.len(),
+                max: MAX_QUERY_PAYLOAD_LEN,
+            });
+        }
+
+        let id = self.meta.next_seq();
+        let ltime = self.meta.query_clock.increment();
+        let msg = Message::Query {
+            id: id,
+            ltime: ltime,
+            from: self.meta.config.name.clone(),
+            addr: self.meta.advertise_addr,
+            name: name,
+            payload: payload,
+            filter: filter,
+            relay_factor: self.meta.config.query_relay_factor,
+        };
+
+        if let Err(e) = self.check_broadcast_size("Query", &msg, UDP_MAX_SIZE) {
+            return Err(e);
+        }
+
+        let responses = self.meta.queries.register(id, ltime, timeout, &self.meta.acks);
+
+        // Handled like a received query, so it is gossiped and answered by
+        // the local node too
+        let mut gossip = self.gossip.clone();
+        MembershipMeta::handle_message(&self.meta, &mut gossip, msg, self.meta.advertise_addr);
+
+        Ok(responses)
+    }
+
+    /// Broadcast the user event `name` with `payload` to the cluster. It is
+    /// disseminated by gossip and handed to the `EventDelegate` of every
+    /// member, the local one included. Fails if the encoded event is larger
+    /// than `user_event_size_limit`, or if `user_event_rate_limit` events
+    /// were broadcast within the last second. A failed event uses up
+    /// neither the rate limit nor a Lamport time.
+    pub fn broadcast(&self, name: String, payload: Vec<u8>) -> ErosionResult<()> {
+        if payload.len() > MAX_PAYLOAD_LEN {
+            return Err(Error::TooLong {
+                field: "Payload",
+                len: payload.len(),
+                max: MAX_PAYLOAD_LEN,
+            });
+        }
+
+        // Sized with the largest Lamport time, as the event only takes one
+        // once it passed the rate limit
+        let mut msg = Message::UserEvent {
+            ltime: u64::MAX,
+            name: name,
+            payload: payload,
+        };
+        if let Err(e) = self.check_broadcast_size("User event", &msg,
+                                                  self.meta.config.user_event_size_limit) {
+            return Err(e);
+        }
+
+        if !self.meta.event_rate.allow() {
+            return Err(Error::RateLimited);
+        }
+
+        if let Message::UserEvent { ref mut ltime, .. } = msg {
+            (*ltime) = self.meta.event_clock.increment();
+        }
+
+        // Handled like a received event, so it is gossiped and delivered
+        // locally too
+        let mut gossip = self.gossip.clone();
+        MembershipMeta::handle_message(&self.meta, &mut gossip, msg, self.meta.advertise_addr);
+        Ok(())
+    }
+
+    /// Check that `msg` is at most `limit` bytes, and fits into a compound
+    /// message as it is piggybacked like any broadcast.
+    fn check_broadcast_size(&self, field: &'static str, msg: &Message,
+                            limit: uint) -> ErosionResult<()> {
+        let buf = self.meta.config.wire_format.codec_for(msg).encode(msg);
+        if let Err(e) = buf {
+            return Err(e);
+        }
+
+        let len = buf.unwrap().len();
+        let max = cmp::min(limit, broadcast_room(self.gossip.payload_size()));
+        if len > max {
+            return Err(Error::TooLong {
+                field: field,
+                len: len,
+                max: max,
+            });
+        }
+        Ok(())
+    }
+
+    /// Join a existing cluster through the member `name` at `addr`. Fails,
+    /// without starting, if the member rejects us or doesn't answer within
+    /// `join_timeout`. With `ignore_old_events`, the user events broadcast
+    /// before we joined are not delivered, even if they are still gossiped.
+    pub fn join(&mut self, name: String, addr: SocketAddr,
+                ignore_old_events: bool) -> ErosionResult<()> {
         if self.started {
-            return;
+            return Err(Error::Join("Already started".to_string()));
+        }
+
+        // The answer is only received while listening
+        self.start_gossip_listening();
+        let result = self.send_join(name, addr, ignore_old_events);
+        if result.is_err() {
+            self.stop_gossip_listening();
+        }
+        result
+    }
+
+    /// Join through `addr`, with the listeners already running.
+    fn send_join(&mut self, name: String, addr: SocketAddr,
+                 ignore_old_events: bool) -> ErosionResult<()> {
+
+        let seq = self.meta.next_seq();
+        let (tx, rx) = channel();
+        self.meta.acks.register(seq, self.meta.config.join_timeout, box JoinHandler {
+            result: tx,
+        });
+
+        let inc = *self.meta.inc.lock();
+        let local_name = self.meta.config.name.clone();
+        let tags = self.meta.tags.lock().clone();
+        let ltime = self.meta.clock.increment();
+        if let Err(e) = self.gossip.join(seq, inc, local_name, self.meta.advertise_addr, tags,
+                                         ltime, addr) {
+            self.meta.acks.cancel(seq);
+            return Err(e);
+        }
+
+        let payload = rx.recv();
+        if let Err(e) = payload {
+            return Err(e);
         }
 
-        let member = Member {
+        let mut member = Member {
             name: name,
             addr: addr,
             state: MemberState::Alive,
             inc: 0,
+            state_change: time::get_time(),
+            tags: BTreeMap::new(),
+            ltime: 0,
         };
+        match JoinAnswer::decode(payload.unwrap().as_slice()) {
+            Ok(answer) => {
+                // Take the incarnation we were vouched for with, or our
+                // Alive message would be stale
+                {
+                    let mut inc = self.meta.inc.lock();
+                    if answer.inc > *inc {
+                        (*inc) = answer.inc;
+                    }
+                }
+                self.meta.event_clock.witness(answer.event_ltime);
+                self.meta.query_clock.witness(answer.query_ltime);
+                if ignore_old_events {
+                    (*self.meta.event_min_ltime.lock()) = answer.event_ltime;
+                }
 
-        {
-            let mut members = self.meta.members.write();
-            members.push(member);
+                match codec::decode(answer.alive.as_slice()) {
+                    Ok(Message::Alive { inc, name, tags, ltime, .. }) if name == member.name => {
+                        self.meta.clock.witness(ltime);
+                        member.inc = inc;
+                        member.tags = tags;
+                        member.ltime = ltime;
+                    },
+                    Ok(msg) => warn!("Unexpected answer to join from {} => {}", addr, msg),
+                    Err(e) => error!("Failed to decode answer to join from {}. Err: {}", addr, e),
+                }
+            },
+            Err(e) => error!("Failed to decode answer to join from {}. Err: {}", addr, e),
+        }
+
+        let joined = member.clone();
+        if self.meta.members.insert(member) {
+            self.meta.notify_member_event(MemberEventKind::Join, joined);
         }
 
         self.start();
+        Ok(())
     }
 
     fn start_probing(&mut self) {
@@ -110,9 +623,35 @@ impl Membership {
         Thread::spawn(move || {
             let mut timer = Timer::new().unwrap();
             let timeout = timer.periodic(meta.config.probe_interval);
+            let (tx, rx) = channel();
+            let events = ProbeEvents {
+                tx: tx,
+                rx: rx,
+            };
+
+            while !meta.is_shutdown() {
+                meta.probe(&mut gossip, &events);
+
+                timeout.recv();
+            }
+
+            ()
+        }).detach();
+    }
+
+    fn start_gossiping(&mut self) {
+        if self.meta.config.gossip_interval.is_zero() {
+            return;
+        }
+
+        let meta = self.meta.clone();
+        let mut gossip = self.gossip.clone();
+        Thread::spawn(move || {
+            let mut timer = Timer::new().unwrap();
+            let timeout = timer.periodic(meta.config.gossip_interval);
 
-            loop {
-                meta.probe(&mut gossip);
+            while !meta.is_shutdown() {
+                meta.gossip(&mut gossip);
 
                 timeout.recv();
             }
@@ -122,17 +661,26 @@ impl Membership {
     }
 
     fn start_gossip_listening(&mut self) {
+        if self.listening.is_some() {
+            return;
+        }
+        let stopped = Arc::new(AtomicBool::new(false));
+        self.listening = Some(stopped.clone());
+
         let meta = self.meta.clone();
         let (message_tx, message_rx) = channel();
         let mut gossip = self.gossip.clone();
-        // Handle messages
+        // Handle messages, until every receiver is gone
         Thread::spawn(move || {
             let (tx, rx) = channel();
             message_tx.send(tx);
 
-            loop {
-                let (msg, from) = rx.recv();
-                meta.handle_message(&mut gossip, msg, from);
+            while !meta.is_shutdown() {
+                let (msg, from) = match rx.recv_opt() {
+                    Ok(received) => received,
+                    Err(()) => break,
+                };
+                MembershipMeta::handle_message(&meta, &mut gossip, msg, from);
             }
 
             ()
@@ -140,126 +688,1876 @@ impl Membership {
 
         let tx = message_rx.recv();
 
-        let mut gossip = self.gossip.clone();
-        // Receiver message from network
-        Thread::spawn(move || {
-            loop {
-                if let Ok((msg, from)) = gossip.recv_from() {
-                    tx.send((msg, from));
+        for tcp in self.tcp.iter() {
+            let meta = self.meta.clone();
+            let mut gossip = self.gossip.clone();
+            let mut tcp = tcp.clone();
+            let stream_tx = tx.clone();
+            let stream_stopped = stopped.clone();
+            // Receive messages which don't fit into a packet
+            Thread::spawn(move || {
+                while !meta.is_shutdown() && !stream_stopped.load(Ordering::Relaxed) {
+                    // The timeout is a deadline rather than a duration, so it
+                    // is set again before every accept
+                    tcp.set_timeout(Some(SHUTDOWN_POLL_MS));
+                    let mut stream = match tcp.accept() {
+                        Ok(stream) => stream,
+                        Err(_) => continue,
+                    };
+
+                    let timeout = meta.config.tcp_timeout.num_milliseconds() as u64;
+                    stream.set_read_timeout(Some(timeout));
+                    if let Ok((msg, from)) = gossip.read_stream(&mut stream) {
+                        if stream_tx.send_opt((msg, from)).is_err() {
+                            break;
+                        }
+                    }
                 }
-            }
 
-            ()
-        }).detach();
+                ()
+            }).detach();
+        }
+
+        for mut gossip in self.gossip.receivers().into_iter() {
+            let meta = self.meta.clone();
+            let tx = tx.clone();
+            let stopped = stopped.clone();
+            // Receiver message from network
+            Thread::spawn(move || {
+                // Wake up now and then to notice a shutdown
+                gossip.udp.set_read_timeout(Some(SHUTDOWN_POLL_MS));
+
+                let mut buf = Vec::from_elem(RECV_BUF_SIZE, 0u8);
+                while !meta.is_shutdown() && !stopped.load(Ordering::Relaxed) {
+                    if let Ok((msg, from)) = gossip.recv_from(buf.as_mut_slice()) {
+                        if tx.send_opt((msg, from)).is_err() {
+                            break;
+                        }
+                    }
+                }
+
+                ()
+            }).detach();
+        }
     }
-}
 
-struct MembershipMeta {
-    config: Config,
+    /// Stop the threads started by `start_gossip_listening`. They notice
+    /// within `SHUTDOWN_POLL_MS`.
+    fn stop_gossip_listening(&mut self) {
+        if let Some(stopped) = self.listening.take() {
+            stopped.store(true, Ordering::Relaxed);
+        }
+    }
+}
 
-    members: RWLock<Vec<Member>>,
+/// What the probing thread is told about the ping it is waiting for.
+enum ProbeEvent {
+    Ack {
+        payload: Vec<u8>,
+        rtt: Duration,
+    },
+    Nack,
+    Timeout,
+}
 
-    ack_senders: Mutex<HashMap<u32, Sender<()>>>,
+/// Where the news of a member being alive comes from.
+#[deriving(Copy, PartialEq)]
+enum AliveOrigin {
+    /// The local node, which is the authority on its own state
+    Local,
 
-    /// local sequence number
-    seq: Mutex<u32>,
+    /// A node joining through us
+    Join,
 
-    probe_index: Mutex<uint>,
+    /// An Alive message gossiped by any member
+    Gossip,
 }
 
-impl MembershipMeta {
-    fn handle_message(&self, gossip: &mut Gossip, msg: Message, from: SocketAddr) {
-        match msg {
-            Message::Ping {
-                seq,
-                name,
-            } => {
-                if name != self.config.name {
-                    error!("Got ping for unexpected member `{}`", name);
-                    return;
-                }
-                gossip.ack(seq, from);
-            },
+/// What the member we join through answers: its own Alive message, the
+/// incarnation it accepted us with, which is newer than ours if it vouched
+/// for us, and the times of its event and query clocks, so ours catch up.
+/// Encoded as a MessagePack map.
+struct JoinAnswer {
+    alive: Vec<u8>,
+    inc: u32,
+    event_ltime: u64,
+    query_ltime: u64,
+}
 
-            Message::Ack {
-                seq,
-            } => {
-                if let Some(sender) = self.ack_senders.lock().remove(&seq) {
-                    sender.send(());
-                }
-            },
+impl JoinAnswer {
+    fn encode(&self) -> Vec<u8> {
+        let fields = vec![
+            (Value::Str("alive".to_string()), Value::Bin(self.alive.clone())),
+            (Value::Str("inc".to_string()), Value::UInt(self.inc as u64)),
+            (Value::Str("event_ltime".to_string()), Value::UInt(self.event_ltime)),
+            (Value::Str("query_ltime".to_string()), Value::UInt(self.query_ltime)),
+        ];
 
-            _ => {},
+        let mut buf = Vec::new();
+        if let Err(e) = write_value(&mut buf, &Value::Map(fields)) {
+            error!("Failed to encode join answer. Err: {}", e);
         }
+        buf
     }
 
-    /// Used to perform a single round of failure detection and gossip
-    fn probe(&self, gossip: &mut Gossip) {
-        let probe_index = { *self.probe_index.lock() };
-        let members_len = { self.members.read().len() };
-
-        if probe_index >= members_len {
-            self.reset_members();
-            (*self.probe_index.lock()) = 0;
+    fn decode(buf: &[u8]) -> ErosionResult<JoinAnswer> {
+        let mut buf = buf;
+        let value = read_value(&mut buf);
+        if let Err(e) = value {
+            return Err(e);
         }
+        let value = value.unwrap();
 
-        let mut member_to_be_probed = None;
-        {
-            let members = self.members.read();
-            let mut probe_index = self.probe_index.lock();
-            while *probe_index < members.len() {
-                let member = &members[*probe_index];
-                (*probe_index) += 1;
-                if member.name == self.config.name
-                   || member.state == MemberState::Dead {
-                    continue;
-                }
-                member_to_be_probed = Some((*member).clone())
+        let alive = match value.get("alive").and_then(|alive| alive.as_bin()) {
+            Some(alive) => alive.to_vec(),
+            None => return Err(Error::InvalidField("alive")),
+        };
+        let ltime = |key: &str| value.get(key).and_then(|ltime| ltime.as_u64()).unwrap_or(0);
+
+        // Older members don't send the incarnation
+        let inc = match value.get("inc").and_then(|inc| inc.as_u64()) {
+            Some(inc) if inc > u32::MAX as u64 => return Err(Error::InvalidField("inc")),
+            Some(inc) => inc as u32,
+            None => 0,
+        };
+
+        Ok(JoinAnswer {
+            alive: alive,
+            inc: inc,
+            event_ltime: ltime("event_ltime"),
+            query_ltime: ltime("query_ltime"),
+        })
+    }
+}
+
+/// What a member attaches to its acks: its network coordinate, and the
+/// payload of its ping delegate. Both are optional, so it is encoded as a
+/// MessagePack map.
+struct AckPayload {
+    coord: Option<Coordinate>,
+    data: Vec<u8>,
+}
+
+impl AckPayload {
+    fn encode(&self) -> Vec<u8> {
+        let mut fields = Vec::new();
+        if let Some(ref coord) = self.coord {
+            let mut buf = Vec::new();
+            match coord.write(&mut buf) {
+                Ok(()) => fields.push((Value::Str("coord".to_string()), Value::Bin(buf))),
+                Err(e) => error!("Failed to encode coordinate. Err: {}", e),
             }
         }
+        if !self.data.is_empty() {
+            fields.push((Value::Str("data".to_string()), Value::Bin(self.data.clone())));
+        }
 
-        if let Some(member) = member_to_be_probed {
-            self.probe_member(gossip, member);
+        let mut buf = Vec::new();
+        if let Err(e) = write_value(&mut buf, &Value::Map(fields)) {
+            error!("Failed to encode ack payload. Err: {}", e);
         }
+        buf
     }
 
-    fn probe_member(&self, gossip: &mut Gossip, member: Member) {
-        info!("Start probing {}", member);
-        let seq = self.next_seq();
-        gossip.ping(seq, member.name, member.addr);
-        if let Some(_) = self.wait_ack(seq, self.config.probe_timeout) {
-            info!("Ack {} confirmed.", seq);
-            return;
+    fn decode(buf: &[u8]) -> ErosionResult<AckPayload> {
+        if buf.is_empty() {
+            return Ok(AckPayload {
+                coord: None,
+                data: Vec::new(),
+            });
+        }
+
+        let mut buf = buf;
+        let value = read_value(&mut buf);
+        if let Err(e) = value {
+            return Err(e);
         }
+        let value = value.unwrap();
 
-        info!("Ack {} timeout.", seq);
+        let coord = match value.get("coord").and_then(|coord| coord.as_bin()) {
+            Some(mut coord) => match Coordinate::read(&mut coord) {
+                Ok(coord) => Some(coord),
+                Err(e) => return Err(e),
+            },
+            None => None,
+        };
+        let data = value.get("data").and_then(|data| data.as_bin())
+                        .map(|data| data.to_vec()).unwrap_or(Vec::new());
+
+        Ok(AckPayload {
+            coord: coord,
+            data: data,
+        })
     }
+}
 
-    fn wait_ack(&self, seq: u32, duration: Duration) -> Option<()> {
-        let (tx, rx) = channel();
-        self.ack_senders.lock().insert(seq, tx);
-        let mut timer = Timer::new().unwrap();
-        let timeout = timer.oneshot(duration);
-
-        select!(
-            () = rx.recv() => Some(()),
-            () = timeout.recv() => {
-                self.ack_senders.lock().remove(&seq);
-                None
-            }
-        )
+/// The probing thread's end of its ack handlers. It is created once, so
+/// probing doesn't need a channel per ping.
+struct ProbeEvents {
+    tx: Sender<(u32, ProbeEvent)>,
+    rx: Receiver<(u32, ProbeEvent)>,
+}
+
+/// Hands the outcome of one of our own pings to the probing thread.
+struct ProbeHandler {
+    seq: u32,
+    events: Sender<(u32, ProbeEvent)>,
+}
+
+impl AckHandler for ProbeHandler {
+    fn ack(&mut self, payload: Vec<u8>, rtt: Duration) {
+        let _ = self.events.send_opt((self.seq, ProbeEvent::Ack {
+            payload: payload,
+            rtt: rtt,
+        }));
     }
 
-    /// Used when the `probe_index` wraps around. It will reap the dead members
-    /// and shuffle the member list
-    fn reset_members(&self) { // TODO
-        let mut rng = task_rng();
-        rng.shuffle(self.members.write().as_mut_slice());
+    fn nack(&mut self) {
+        let _ = self.events.send_opt((self.seq, ProbeEvent::Nack));
     }
 
-    fn next_seq(&self) -> u32 {
+    fn timeout(&mut self) {
+        let _ = self.events.send_opt((self.seq, ProbeEvent::Timeout));
+    }
+}
+
+/// Hands the answer to our join to the joining thread.
+struct JoinHandler {
+    result: Sender<ErosionResult<Vec<u8>>>,
+}
+
+impl AckHandler for JoinHandler {
+    fn ack(&mut self, payload: Vec<u8>, _rtt: Duration) {
+        let _ = self.result.send_opt(Ok(payload));
+    }
+
+    fn timeout(&mut self) {
+        let _ = self.result.send_opt(Err(Error::Timeout));
+    }
+
+    fn reject(&mut self, reason: String) {
+        let _ = self.result.send_opt(Err(Error::Join(reason)));
+    }
+}
+
+/// Collects the answer to a conflict query: the address the member knows
+/// for the conflicting name, or nothing if it doesn't know or didn't answer.
+struct ConflictHandler {
+    answers: Sender<Option<SocketAddr>>,
+}
+
+impl AckHandler for ConflictHandler {
+    fn ack(&mut self, payload: Vec<u8>, _rtt: Duration) {
+        let addr = String::from_utf8(payload).ok().and_then(|addr| {
+            from_str::<SocketAddr>(addr.as_slice())
+        });
+        let _ = self.answers.send_opt(addr);
+    }
+
+    fn timeout(&mut self) {
+        let _ = self.answers.send_opt(None);
+    }
+}
+
+/// Relays the outcome of a ping sent on behalf of another member, as part of
+/// its indirect probe.
+struct RelayHandler {
+    gossip: Gossip,
+
+    /// The sequence number of the originator's indirect ping
+    seq: u32,
+
+    /// The originator
+    to: SocketAddr,
+}
+
+impl AckHandler for RelayHandler {
+    fn ack(&mut self, payload: Vec<u8>, _: Duration) {
+        if let Err(e) = self.gossip.ack(self.seq, payload, self.to) {
+            error!("Failed to relay ack {} to {}. Err: {}", self.seq, self.to, e);
+        }
+    }
+
+    fn timeout(&mut self) {
+        if let Err(e) = self.gossip.nack(self.seq, self.to) {
+            error!("Failed to send nack {} to {}. Err: {}", self.seq, self.to, e);
+        }
+    }
+}
+
+/// What is remembered about a reaped member.
+struct Tombstone {
+    /// The member as it was when it was reaped
+    member: Member,
+
+    /// When the member was reaped
+    reaped: Timespec,
+}
+
+/// The tuned probe timeout is this many times the 99-percentile RTT.
+const AUTO_PROBE_TIMEOUT_HEADROOM: i32 = 2;
+
+/// The shortest wait, in milliseconds, for the answers to indirect pings.
+const MIN_INDIRECT_PROBE_WAIT_MS: i64 = 10;
+
+/// How often, in milliseconds, the receiving thread checks for a shutdown
+/// while no packets arrive.
+const SHUTDOWN_POLL_MS: u64 = 1000;
+
+/// The number of ports tried when binding to port 0, as the port the OS
+/// picks for UDP may be taken for TCP.
+const EPHEMERAL_BIND_ATTEMPTS: uint = 10;
+
+/// Bind the UDP sockets and the TCP listeners to the same port.
+fn bind_transport(config: &Config) -> ErosionResult<(Gossip, Vec<TcpAcceptor>)> {
+    let mut attempts = 0u;
+    loop {
+        let gossip = Gossip::new(config);
+        if let Err(e) = gossip {
+            return Err(e);
+        }
+        let gossip = gossip.unwrap();
+
+        let mut addrs = vec![gossip.local_addr()];
+        addrs.extend(gossip.ipv4_addr().into_iter());
+        let mut tcp = Vec::new();
+        let mut result = Ok(());
+        for addr in addrs.iter() {
+            match TcpListener::bind(*addr).and_then(|listener| listener.listen()) {
+                Ok(acceptor) => tcp.push(acceptor),
+                Err(e) => {
+                    result = Err((*addr, e));
+                    break;
+                },
+            }
+        }
+
+        match result {
+            Ok(()) => return Ok((gossip, tcp)),
+            Err((addr, e)) => {
+                attempts += 1;
+                if config.bind_addr.port != 0 || attempts == EPHEMERAL_BIND_ATTEMPTS {
+                    return Err(Error::Bind {
+                        addr: addr,
+                        err: e,
+                    });
+                }
+                warn!("Failed to bind TCP to {}, retrying. Err: {}", addr, e);
+            },
+        }
+    }
+}
+
+/// Returns the configured advertise address, or else the bound address
+/// with a wildcard IP replaced by a private interface address. A port 0 is
+/// replaced by the bound port.
+fn advertise_addr(config: &Config, bound: SocketAddr) -> ErosionResult<SocketAddr> {
+    if let Some(addr) = config.advertise_addr {
+        let port = if addr.port == 0 { bound.port } else { addr.port };
+        return Ok(SocketAddr {
+            ip: addr.ip,
+            port: port,
+        });
+    }
+
+    let addr = bound;
+    if !ifaddr::is_unspecified(&addr.ip) {
+        return Ok(addr);
+    }
+
+    // An IPv6 wildcard socket may advertise an IPv6 address, if it can be
+    // encoded
+    let ipv6 = addr.ip != Ipv4Addr(0, 0, 0, 0) && config.wire_format != WireFormat::Legacy;
+    match ifaddr::private_addr(ipv6) {
+        Some(ip) => Ok(SocketAddr {
+            ip: ip,
+            port: addr.port,
+        }),
+        None => Err(Error::NoAdvertiseAddr),
+    }
+}
+
+/// Returns the largest message which is piggybacked into a packet with
+/// `payload_size` bytes of room, as the only part of a compound message.
+fn broadcast_room(payload_size: uint) -> uint {
+    payload_size.checked_sub(COMPOUND_HEADER_OVERHEAD + COMPOUND_PART_OVERHEAD).unwrap_or(0)
+}
+
+/// Check that our Alive message with `tags` fits into a packet, and into the
+/// answer to a join, which is carried in an ack payload.
+fn check_tags(config: &Config, addr: SocketAddr, payload_size: uint,
+              tags: &BTreeMap<String, String>) -> ErosionResult<()> {
+    // As large as our Alive message ever gets
+    let alive = config.wire_format.codec().encode(&Message::Alive {
+        inc: u32::MAX,
+        name: config.name.clone(),
+        addr: addr,
+        tags: tags.clone(),
+        ltime: u64::MAX,
+    });
+    if let Err(e) = alive {
+        return Err(e);
+    }
+    let alive = alive.unwrap();
+
+    let max = broadcast_room(payload_size);
+    if alive.len() > max {
+        return Err(Error::TooLong {
+            field: "Alive message",
+            len: alive.len(),
+            max: max,
+        });
+    }
+
+    let answer = JoinAnswer {
+        alive: alive,
+        inc: u32::MAX,
+        event_ltime: u64::MAX,
+        query_ltime: u64::MAX,
+    }.encode();
+    if answer.len() > MAX_PAYLOAD_LEN {
+        return Err(Error::TooLong {
+            field: "Join answer",
+            len: answer.len(),
+            max: MAX_PAYLOAD_LEN,
+        });
+    }
+    Ok(())
+}
+
+struct MembershipMeta {
+    config: Config,
+
+    /// The address carried in our Alive messages
+    advertise_addr: SocketAddr,
+
+    members: MemberStore,
+
+    /// Reaped members, kept for `tombstone_retention`
+    tombstones: Mutex<HashMap<String, Tombstone>>,
+
+    /// Pending state changes to gossip
+    broadcasts: Mutex<TransmitLimitedQueue>,
+
+    acks: AckRouter,
+
+    /// Our queries waiting for answers, and the queries we answered
+    queries: Queries,
+
+    /// Round-trip times measured by probing
+    latencies: Latencies,
+
+    /// Network coordinates, updated from the same RTTs
+    coordinates: Coordinates,
+
+    /// Health of the local node, raised by missed nacks and failed probes
+    awareness: Awareness,
+
+    /// local sequence number
+    seq: Mutex<u32>,
+
+    /// Local incarnation number
+    inc: Mutex<u32>,
+
+    /// Local tags, changed along with `inc`
+    tags: Mutex<BTreeMap<String, String>>,
+
+    /// Orders joins, leaves and deaths
+    clock: LamportClock,
+
+    /// Orders user events
+    event_clock: LamportClock,
+
+    /// Orders queries
+    query_clock: LamportClock,
+
+    /// User events received recently, as gossip delivers them several times
+    recent_events: Mutex<RecentEvents<(String, Vec<u8>)>>,
+
+    /// User events before this Lamport time are not delivered, see `join`
+    event_min_ltime: Mutex<u64>,
+
+    /// Set if user events are coalesced before they are delivered
+    event_coalescer: Option<UserEventCoalescer>,
+
+    /// Limits the user events we broadcast
+    event_rate: RateLimiter,
+
+    /// Set if member events are coalesced before they are delivered
+    member_coalescer: Option<MemberEventCoalescer>,
+
+    /// Set while the cluster is queried about a conflict on our name
+    resolving_conflict: AtomicBool,
+
+    /// Set once the local node is shut down, which stops every thread
+    shutdown: AtomicBool,
+}
+
+impl MembershipMeta {
+    fn handle_message(meta: &Arc<MembershipMeta>, gossip: &mut Gossip,
+                      msg: Message, from: SocketAddr) {
+        match msg {
+            Message::Ping {
+                seq,
+                name,
+            } => {
+                if name != meta.config.name {
+                    error!("Got ping for unexpected member `{}`", name);
+                    return;
+                }
+                let payload = meta.ack_payload().encode();
+                if let Err(e) = gossip.ack(seq, payload, from) {
+                    error!("Failed to ack {} to {}. Err: {}", seq, from, e);
+                }
+            },
+
+            Message::IndirectPing {
+                addr,
+                seq,
+                name,
+            } => {
+                meta.indirect_probe(gossip, addr, seq, name, from);
+            },
+
+            Message::Ack {
+                seq,
+                payload,
+            } => {
+                meta.acks.ack(seq, payload);
+            },
+
+            Message::Nack {
+                seq,
+            } => {
+                meta.acks.nack(seq);
+            },
+
+            Message::Suspect {
+                inc,
+                name,
+                from,
+            } => {
+                meta.suspect_node(inc, name, from);
+            },
+
+            Message::Alive {
+                inc,
+                name,
+                addr,
+                tags,
+                ltime,
+            } => {
+                meta.clock.witness(ltime);
+                if name == meta.config.name && addr != meta.advertise_addr {
+                    MembershipMeta::resolve_conflict(meta, gossip);
+                }
+                let _ = meta.alive_node(inc, name, addr, tags, ltime, AliveOrigin::Gossip);
+            },
+
+            Message::Dead {
+                inc,
+                name,
+                from,
+                ltime,
+            } => {
+                meta.clock.witness(ltime);
+                meta.dead_node(inc, name, from, ltime);
+            },
+
+            Message::Compound {
+                msgs,
+            } => {
+                for msg in msgs.into_iter() {
+                    MembershipMeta::handle_message(meta, gossip, msg, from);
+                }
+            },
+
+            Message::Join {
+                seq,
+                inc,
+                name,
+                addr,
+                tags,
+                ltime,
+            } => {
+                let sent = match meta.handle_join(inc, name.clone(), addr, tags, ltime, from) {
+                    Ok(inc) => gossip.ack(seq, meta.join_answer(inc).encode(), from),
+                    Err(reason) => {
+                        info!("Rejected join of {} from {}: {}", name, from, reason);
+                        gossip.reject(seq, reason, from)
+                    },
+                };
+                if let Err(e) = sent {
+                    error!("Failed to answer join of {} to {}. Err: {}", name, from, e);
+                }
+            },
+
+            Message::Reject {
+                seq,
+                reason,
+            } => {
+                meta.acks.reject(seq, reason);
+            },
+
+            Message::ConflictQuery {
+                seq,
+                name,
+            } => {
+                let payload = match meta.members.get(name.as_slice()) {
+                    Some(member) => member.read().addr.to_string().into_bytes(),
+                    None => Vec::new(),
+                };
+                if let Err(e) = gossip.ack(seq, payload, from) {
+                    error!("Failed to answer conflict query to {}. Err: {}", from, e);
+                }
+            },
+
+            Message::Query {
+                id,
+                ltime,
+                from: origin,
+                addr,
+                name,
+                payload,
+                filter,
+                relay_factor,
+            } => {
+                meta.query_clock.witness(ltime);
+                let now = meta.query_clock.time();
+                if !meta.queries.witness(origin.as_slice(), id, addr, ltime, now) {
+                    return;
+                }
+
+                // Keep the query spreading, whether or not it is for us
+                meta.queue_unique_broadcast(Message::Query {
+                    id: id,
+                    ltime: ltime,
+                    from: origin.clone(),
+                    addr: addr,
+                    name: name.clone(),
+                    payload: payload.clone(),
+                    filter: filter.clone(),
+                    relay_factor: relay_factor,
+                });
+
+                let local = meta.members.get(meta.config.name.as_slice());
+                if local.is_none() || !filter.matches(&*local.unwrap().read()) {
+                    return;
+                }
+
+                meta.answer_query(gossip, id, addr, relay_factor, true, Vec::new());
+
+                let response = match meta.config.query_delegate {
+                    Some(ref delegate) => {
+                        delegate.handle_query(origin.as_slice(), name.as_slice(),
+                                              payload.as_slice())
+                    },
+                    None => None,
+                };
+                if let Some(response) = response {
+                    if response.len() > MAX_QUERY_PAYLOAD_LEN {
+                        error!("Dropped response of {} bytes to query {}, at most {} are allowed",
+                               response.len(), name, MAX_QUERY_PAYLOAD_LEN);
+                        return;
+                    }
+                    meta.answer_query(gossip, id, addr, relay_factor, false, response);
+                }
+            },
+
+            Message::QueryResponse {
+                id,
+                from: responder,
+                ack,
+                payload,
+            } => {
+                meta.queries.answer(id, responder, ack, payload);
+            },
+
+            Message::Relay {
+                addr,
+                msg,
+            } => {
+                // Only answers to a query we passed on are relayed, and only
+                // to where the query asked for them, so we can't be used to
+                // send anything anywhere. A query which hasn't reached us yet
+                // loses this relay, but its answer is also sent directly.
+                let relayable = match codec::decode(msg.as_slice()) {
+                    Ok(Message::QueryResponse { id, .. }) => meta.queries.is_relayable(id, addr),
+                    _ => false,
+                };
+                if !relayable {
+                    warn!("Refused to relay message from {} to {}", from, addr);
+                    return;
+                }
+
+                if let Err(e) = gossip.forward(msg.as_slice(), addr) {
+                    error!("Failed to relay message from {} to {}. Err: {}", from, addr, e);
+                }
+            },
+
+            Message::UserEvent {
+                ltime,
+                name,
+                payload,
+            } => {
+                meta.event_clock.witness(ltime);
+                let now = meta.event_clock.time();
+                let event = (name.clone(), payload.clone());
+                if !meta.recent_events.lock().witness(ltime, now, event) {
+                    return;
+                }
+
+                meta.queue_unique_broadcast(Message::UserEvent {
+                    ltime: ltime,
+                    name: name.clone(),
+                    payload: payload.clone(),
+                });
+
+                if ltime < *meta.event_min_ltime.lock() {
+                    info!("Ignoring user event {} from before we joined", name);
+                    return;
+                }
+                if let Some(ref coalescer) = meta.event_coalescer {
+                    coalescer.push(ltime, name, payload);
+                } else if let Some(ref delegate) = meta.config.event_delegate {
+                    delegate.notify_user_event(ltime, name.as_slice(), payload.as_slice());
+                }
+            },
+
+            _ => {},
+        }
+    }
+
+    /// Send an ack or a response to the query `id` to the querying node at
+    /// `to`, and relay it through `relay_factor` random members.
+    fn answer_query(&self, gossip: &mut Gossip, id: u32, to: SocketAddr, relay_factor: u8,
+                    ack: bool, payload: Vec<u8>) {
+        let msg = Message::QueryResponse {
+            id: id,
+            from: self.config.name.clone(),
+            ack: ack,
+            payload: payload,
+        };
+        let buf = match self.config.wire_format.codec_for(&msg).encode(&msg) {
+            Ok(buf) => buf,
+            Err(e) => {
+                error!("Failed to encode answer to query {}. Err: {}", id, e);
+                return;
+            },
+        };
+
+        let sent = if buf.len() <= gossip.payload_size() {
+            gossip.forward(buf.as_slice(), to)
+        } else {
+            gossip.send_tcp(&msg, to, self.config.tcp_timeout)
+        };
+        if let Err(e) = sent {
+            error!("Failed to answer query {} of {}. Err: {}", id, to, e);
+        }
+
+        if relay_factor == 0 {
+            return;
+        }
+
+        let local_name = self.config.name.as_slice();
+        let relays = self.members.random_members(relay_factor as uint, |member| {
+            member.state == MemberState::Alive
+            && member.name.as_slice() != local_name
+            && member.addr != to
+        });
+        for relay in relays.iter() {
+            if let Err(e) = gossip.relay(to, buf.clone(), relay.addr) {
+                error!("Failed to relay answer to query {} through {}. Err: {}",
+                       id, relay.name, e);
+            }
+        }
+    }
+
+    /// Ping `addr` on behalf of the member at `from`, then relay an ack if the
+    /// target answers in time, or a nack if it does not.
+    fn indirect_probe(&self, gossip: &mut Gossip, addr: SocketAddr, seq: u32,
+                      name: String, from: SocketAddr) {
+        let local_seq = self.next_seq();
+        self.acks.register(local_seq, self.config.probe_timeout, box RelayHandler {
+            gossip: gossip.clone(),
+            seq: seq,
+            to: from,
+        });
+
+        // We can't reach the target at all, so nack right away instead of
+        // letting the originator wait.
+        if let Err(e) = gossip.ping(local_seq, name, addr) {
+            error!("Failed to ping {} for {}. Err: {}", addr, from, e);
+            self.acks.cancel(local_seq);
+            if let Err(e) = gossip.nack(seq, from) {
+                error!("Failed to send nack {} to {}. Err: {}", seq, from, e);
+            }
+        }
+    }
+
+    /// Used to perform a single round of failure detection and gossip
+    fn probe(&self, gossip: &mut Gossip, events: &ProbeEvents) {
+        self.check_suspects();
+
+        if let Some(member) = self.next_to_probe() {
+            self.probe_member(gossip, events, member);
+        }
+    }
+
+    /// Returns the next member in the probe order which can be probed,
+    /// starting a new round once the order is exhausted. Every member is
+    /// checked at most once per round, and a new round is started at most
+    /// once, so a list with nobody to probe doesn't spin forever.
+    fn next_to_probe(&self) -> Option<Member> {
+        let mut checked = 0u;
+        let mut wrapped = false;
+        while checked < self.members.len() {
+            let name = match self.members.next_to_probe() {
+                Some(name) => name,
+                None => {
+                    if wrapped {
+                        return None;
+                    }
+                    wrapped = true;
+                    checked = 0;
+                    self.reset_members();
+                    continue;
+                },
+            };
+            checked += 1;
+
+            if let Some(member) = self.members.get(name.as_slice()) {
+                let member = member.read();
+                if member.name != self.config.name
+                   && member.state != MemberState::Dead
+                   && member.state != MemberState::Left {
+                    return Some(member.clone());
+                }
+            }
+        }
+        None
+    }
+
+    fn probe_member(&self, gossip: &mut Gossip, events: &ProbeEvents, member: Member) {
+        info!("Start probing {}", member);
+        let probe_interval = self.awareness.scale_timeout(self.config.probe_interval);
+        let probe_timeout = self.awareness.scale_timeout(self.probe_timeout());
+
+        // Register the ack handler before sending the ping, so an early ack
+        // is never missed.
+        let seq = self.next_seq();
+        self.acks.register(seq, probe_timeout, box ProbeHandler {
+            seq: seq,
+            events: events.tx.clone(),
+        });
+
+        // Failing to send says nothing about the member, so don't hold it
+        // against it. It is our own network which is broken.
+        if let Err(e) = gossip.ping(seq, member.name.clone(), member.addr) {
+            error!("Failed to ping {}, skipping it this round. Err: {}", member, e);
+            self.acks.cancel(seq);
+            self.note_send_failure(&e);
+            return;
+        }
+
+        if let (Some((payload, rtt)), _) = self.wait_probe(events, seq) {
+            info!("Ack {} confirmed in {}.", seq, rtt);
+            self.latencies.record(member.name.as_slice(), rtt);
+            match AckPayload::decode(payload.as_slice()) {
+                Ok(payload) => {
+                    if let (true, Some(coord)) = (self.config.enable_coordinates, payload.coord) {
+                        self.coordinates.update(member.name.as_slice(), coord, rtt);
+                    }
+                    if let Some(ref delegate) = self.config.ping_delegate {
+                        delegate.notify_ping_complete(&member, rtt, payload.data.as_slice());
+                    }
+                },
+                Err(e) => error!("Failed to decode ack payload of {}. Err: {}", member.name, e),
+            }
+            self.awareness.apply_delta(-1);
+            return;
+        }
+
+        info!("Ack {} timeout, probing {} indirectly.", seq, member);
+        // The rest of the probe interval, which `bind` makes sure is left
+        let wait = cmp::max(probe_interval - probe_timeout,
+                            Duration::milliseconds(MIN_INDIRECT_PROBE_WAIT_MS));
+        self.acks.register(seq, wait, box ProbeHandler {
+            seq: seq,
+            events: events.tx.clone(),
+        });
+        // Only the helpers we actually reached will nack.
+        let mut expected_nacks = 0u;
+        for helper in self.indirect_helpers(&member).iter() {
+            match gossip.indirect_ping(seq, member.name.clone(), member.addr, helper.addr) {
+                Ok(()) => expected_nacks += 1,
+                Err(e) => error!("Failed to send indirect ping to {}. Err: {}", helper, e),
+            }
+        }
+
+        // The RTT of an indirect ack includes the helper, so it is not
+        // recorded.
+        let (ack, nacks) = self.wait_probe(events, seq);
+        if ack.is_some() {
+            info!("Ack {} confirmed indirectly.", seq);
+            self.awareness.apply_delta(-1);
+            return;
+        }
+
+        // Every helper that could not reach the target sends a nack, so the
+        // nacks we miss are a sign that our own network is broken.
+        if expected_nacks > 0 {
+            if nacks < expected_nacks {
+                self.awareness.apply_delta((expected_nacks - nacks) as int);
+            }
+        } else {
+            self.awareness.apply_delta(1);
+        }
+
+        info!("Probe {} failed, no acks received.", member);
+        self.suspect_node(member.inc, member.name, self.config.name.clone());
+    }
+
+    /// Pick up to `indirect_checks` random alive members, other than
+    /// ourselves and `target`, to probe `target` on our behalf.
+    fn indirect_helpers(&self, target: &Member) -> Vec<Member> {
+        self.members.random_members(self.config.indirect_checks, |member| {
+            member.name != self.config.name
+            && member.name != target.name
+            && member.state == MemberState::Alive
+        })
+    }
+
+    /// The probe timeout before it is scaled by the local health.
+    ///
+    /// Only acks which arrived in time are measured, so the percentile
+    /// underestimates the real RTTs. It gets headroom, and never goes below
+    /// the configured timeout, or the timeout would keep shrinking until
+    /// healthy members get suspected.
+    fn probe_timeout(&self) -> Duration {
+        if !self.config.auto_probe_timeout {
+            return self.config.probe_timeout;
+        }
+
+        match self.latencies.cluster_percentile(0.99) {
+            Some(p99) => {
+                let tuned = cmp::min(p99 * AUTO_PROBE_TIMEOUT_HEADROOM,
+                                     self.config.probe_interval / 2);
+                cmp::max(tuned, self.config.probe_timeout)
+            },
+            None => self.config.probe_timeout,
+        }
+    }
+
+    /// Wait until the ping `seq` is acked or times out. Returns the payload
+    /// and RTT if it was acked, and the number of nacks received meanwhile.
+    fn wait_probe(&self, events: &ProbeEvents, seq: u32)
+                  -> (Option<(Vec<u8>, Duration)>, uint) {
+        let mut nacks = 0u;
+        loop {
+            let (event_seq, event) = events.rx.recv();
+
+            // Left over from an earlier ping
+            if event_seq != seq {
+                continue;
+            }
+
+            match event {
+                ProbeEvent::Ack { payload, rtt } => return (Some((payload, rtt)), nacks),
+                ProbeEvent::Nack => nacks += 1,
+                ProbeEvent::Timeout => return (None, nacks),
+            }
+        }
+    }
+
+    /// Send pending broadcasts to `gossip_nodes` random members. Dead
+    /// members which have not been reaped yet are included, so they get a
+    /// chance to refute.
+    fn gossip(&self, gossip: &mut Gossip) {
+        let members_len = self.members.len();
+        let targets = self.members.random_members(self.config.gossip_nodes, |member| {
+            member.name != self.config.name
+            && member.state != MemberState::Left
+        });
+
+        for target in targets.iter() {
+            let (ids, msgs) = self.broadcasts.lock().get_broadcasts(
+                gossip.payload_size() - COMPOUND_HEADER_OVERHEAD);
+            if msgs.is_empty() {
+                return;
+            }
+
+            // Messages which were not sent are not counted as transmitted
+            match gossip.compound(msgs.as_slice(), target.addr) {
+                Ok(()) => self.broadcasts.lock().transmitted(ids.as_slice(), members_len),
+                Err(e) => {
+                    error!("Failed to gossip to {}. Err: {}", target, e);
+                    self.note_send_failure(&e);
+                },
+            }
+        }
+    }
+
+    /// A node at `from` asks to join through us. Returns the incarnation we
+    /// vouch for it with, or why it is rejected. Only the node itself may
+    /// join, so `addr` must be where the request comes from.
+    fn handle_join(&self, inc: u32, name: String, addr: SocketAddr,
+                   tags: BTreeMap<String, String>, ltime: u64,
+                   from: SocketAddr) -> Result<u32, String> {
+        if addr != from {
+            return Err(format!("{} joins from {}, not from its address {}", name, from, addr));
+        }
+
+        self.clock.witness(ltime);
+        let result = match self.config.merge_delegate {
+            Some(ref delegate) => delegate.notify_merge(&[Member {
+                name: name.clone(),
+                addr: addr,
+                state: MemberState::Alive,
+                inc: inc,
+                state_change: time::get_time(),
+                tags: tags.clone(),
+                ltime: ltime,
+            }]),
+            None => Ok(()),
+        };
+        if let Err(reason) = result {
+            return Err(reason);
+        }
+
+        match self.alive_node(inc, name.clone(), addr, tags, ltime, AliveOrigin::Join) {
+            Ok(Some(inc)) => Ok(inc),
+            // An ignored Alive message leaves the joiner out, so it must not
+            // think it joined
+            Ok(None) => Err(format!("{} is ignored as stale", name)),
+            Err(reason) => Err(reason),
+        }
+    }
+
+    /// Failing to send a packet at all is a sign that our own network is
+    /// broken, so it lowers the local health like a missed nack.
+    fn note_send_failure(&self, e: &Error) {
+        if let &Error::Transport(..) = e {
+            self.awareness.apply_delta(1);
+        }
+    }
+
+    /// Returns the incarnation the member was updated with, None if the
+    /// message is stale, or an error if the member was rejected, because its
+    /// address conflicts with the one we know or the alive delegate vetoed
+    /// it. See `check_alive` for when a message is stale.
+    fn alive_node(&self, inc: u32, name: String, addr: SocketAddr,
+                  tags: BTreeMap<String, String>, ltime: u64,
+                  origin: AliveOrigin) -> Result<Option<u32>, String> {
+        let now = time::get_time();
+        let mut inc = inc;
+        let mut ltime = ltime;
+
+        if let Some(tombstone) = self.tombstones.lock().get(&name) {
+            match self.check_alive(&tombstone.member, inc, ltime, addr, origin, now) {
+                Ok(Some((checked_inc, checked_ltime))) => {
+                    inc = checked_inc;
+                    ltime = checked_ltime;
+                },
+                Ok(None) => {
+                    info!("Ignoring stale alive message for reaped member {}", name);
+                    return Ok(None);
+                },
+                Err(reason) => {
+                    error!("Conflicting address for reaped member {}. Known: {} Claimed: {}",
+                           name, tombstone.member.addr, addr);
+                    return Err(reason);
+                },
+            }
+        }
+
+        // Delegates are notified once the member is unlocked
+        let mut event = None;
+        match self.members.get(name.as_slice()) {
+            None => {
+                let member = Member {
+                    name: name.clone(),
+                    addr: addr,
+                    state: MemberState::Alive,
+                    inc: inc,
+                    state_change: now,
+                    tags: tags.clone(),
+                    ltime: ltime,
+                };
+
+                if name != self.config.name {
+                    if let Some(ref delegate) = self.config.alive_delegate {
+                        if let Err(reason) = delegate.notify_alive(&member) {
+                            warn!("Rejected {}: {}", member, reason);
+                            return Err(reason);
+                        }
+                    }
+                }
+
+                let joined = member.clone();
+                if !self.members.insert(member) {
+                    return Ok(None);
+                }
+                event = Some((MemberEventKind::Join, joined));
+            },
+
+            Some(member) => {
+                let mut member = member.write();
+
+                // We are the authority on our own state
+                if name == self.config.name && member.addr == addr
+                   && origin != AliveOrigin::Local {
+                    return Ok(None);
+                }
+
+                match self.check_alive(&*member, inc, ltime, addr, origin, now) {
+                    Ok(Some((checked_inc, checked_ltime))) => {
+                        inc = checked_inc;
+                        ltime = checked_ltime;
+                    },
+                    Ok(None) => return Ok(None),
+                    Err(reason) => {
+                        error!("Conflicting address for {}. Known: {} Claimed: {}",
+                               name, member.addr, addr);
+                        if let Some(ref delegate) = self.config.conflict_delegate {
+                            delegate.notify_conflict(&*member, &Member {
+                                name: name.clone(),
+                                addr: addr,
+                                state: MemberState::Alive,
+                                inc: inc,
+                                state_change: now,
+                                tags: tags.clone(),
+                                ltime: ltime,
+                            });
+                        }
+                        return Err(reason);
+                    },
+                }
+                if member.addr != addr {
+                    info!("Member {} reclaimed by {}", name, addr);
+                    member.addr = addr;
+                }
+
+                let kind = if member.state == MemberState::Dead
+                              || member.state == MemberState::Left {
+                    Some(MemberEventKind::Join)
+                } else if member.tags != tags {
+                    Some(MemberEventKind::Update)
+                } else {
+                    None
+                };
+
+                member.inc = inc;
+                member.tags = tags.clone();
+                member.ltime = ltime;
+                if member.state != MemberState::Alive {
+                    member.state = MemberState::Alive;
+                    member.state_change = now;
+                }
+                event = kind.map(|kind| (kind, member.clone()));
+            },
+        }
+
+        // Only now that the member is back, or we would forget a rejected
+        // node's previous life
+        self.tombstones.lock().remove(&name);
+
+        if let Some((kind, member)) = event {
+            self.notify_member_event(kind, member);
+        }
+
+        self.queue_broadcast(name.clone(), Message::Alive {
+            inc: inc,
+            name: name,
+            addr: addr,
+            tags: tags,
+            ltime: ltime,
+        });
+        Ok(Some(inc))
+    }
+
+    /// Check an Alive message from a node at `addr` against `known`, the
+    /// member we know by that name or its tombstone if it was reaped.
+    /// Returns the incarnation and Lamport time to accept the message with,
+    /// None if it is stale, or an error if another node holds the name.
+    ///
+    /// A node with another address may only take over a name under the
+    /// reclaim rules of `can_reclaim`, and starts a life of its own. From
+    /// the known address, a message is stale if its incarnation isn't newer
+    /// than the known one, or its Lamport time is older. Legacy nodes send
+    /// no Lamport time, which decodes as 0 and is never older.
+    ///
+    /// A node which restarted comes back with incarnation 0. If it joins
+    /// through us with an incarnation or time we already know better, we
+    /// vouch for it with a newer incarnation and Lamport time, so its Alive
+    /// message is not dropped as stale by the cluster.
+    fn check_alive(&self, known: &Member, inc: u32, ltime: u64, addr: SocketAddr,
+                   origin: AliveOrigin, now: Timespec) -> Result<Option<(u32, u64)>, String> {
+        if known.addr != addr {
+            if !self.can_reclaim(known.state, known.state_change, now) {
+                return Err(format!("{} is taken by {}", known.name, known.addr));
+            }
+            return Ok(Some((inc, ltime)));
+        }
+
+        let stale_inc = inc <= known.inc;
+        let stale_ltime = ltime != 0 && ltime < known.ltime;
+        if !stale_inc && !stale_ltime {
+            return Ok(Some((inc, ltime)));
+        }
+        if origin != AliveOrigin::Join {
+            return Ok(None);
+        }
+
+        let inc = if stale_inc { known.inc + 1 } else { inc };
+        Ok(Some((inc, self.clock.increment())))
+    }
+
+    fn suspect_node(&self, inc: u32, name: String, from: String) {
+        let member = match self.members.get(name.as_slice()) {
+            Some(member) => member,
+            None => return,
+        };
+        let mut member = member.write();
+
+        // Ignore old incarnation numbers and members which are not alive
+        if inc < member.inc || member.state != MemberState::Alive {
+            return;
+        }
+
+        if name == self.config.name {
+            self.refute(&mut *member, inc);
+            return;
+        }
+
+        info!("Suspecting {} (from {})", name, from);
+        member.inc = inc;
+        member.state = MemberState::Suspect;
+        member.state_change = time::get_time();
+
+        self.queue_broadcast(name.clone(), Message::Suspect {
+            inc: inc,
+            name: name,
+            from: from,
+        });
+    }
+
+    fn dead_node(&self, inc: u32, name: String, from: String, ltime: u64) {
+        let member = match self.members.get(name.as_slice()) {
+            Some(member) => member,
+            None => return,
+        };
+        let mut member = member.write();
+
+        // Ignore old incarnation numbers and members which are already gone
+        if inc < member.inc
+           || member.state == MemberState::Dead
+           || member.state == MemberState::Left {
+            return;
+        }
+
+        // A message from before the member's last join is stale as well,
+        // unless it comes from a legacy node, which sends time 0
+        if ltime != 0 && ltime < member.ltime {
+            return;
+        }
+
+        if name == self.config.name && from != name {
+            self.refute(&mut *member, inc);
+            return;
+        }
+
+        member.inc = inc;
+        member.ltime = ltime;
+        member.state = if from == name {
+            info!("Member {} left", name);
+            MemberState::Left
+        } else {
+            info!("Member {} is dead (from {})", name, from);
+            MemberState::Dead
+        };
+        member.state_change = time::get_time();
+        let left = member.clone();
+        drop(member);
+        self.notify_member_event(MemberEventKind::Leave, left);
+
+        self.queue_broadcast(name.clone(), Message::Dead {
+            inc: inc,
+            name: name,
+            from: from,
+            ltime: ltime,
+        });
+    }
+
+    /// Somebody suspects us or declared us dead, so gossip that we are alive
+    /// with a newer incarnation number.
+    fn refute(&self, member: &mut Member, accused_inc: u32) {
+        let mut inc = self.inc.lock();
+        if accused_inc > *inc {
+            (*inc) = accused_inc;
+        }
+        (*inc) += 1;
+
+        warn!("Refuting an accusation at incarnation {}, now {}", accused_inc, *inc);
+        member.inc = *inc;
+        member.ltime = self.clock.increment();
+        self.queue_broadcast(member.name.clone(), Message::Alive {
+            inc: *inc,
+            name: member.name.clone(),
+            addr: member.addr,
+            tags: member.tags.clone(),
+            ltime: member.ltime,
+        });
+    }
+
+    /// Whether a node with a different address may take over the name of a
+    /// member. This is only allowed once the member has been dead or gone for
+    /// `dead_node_reclaim_time`.
+    fn can_reclaim(&self, state: MemberState, state_change: Timespec, now: Timespec) -> bool {
+        let reclaim_time = self.config.dead_node_reclaim_time;
+        !reclaim_time.is_zero()
+        && (state == MemberState::Dead || state == MemberState::Left)
+        && now - state_change > reclaim_time
+    }
+
+    /// Declare dead every suspect which has not refuted in time.
+    fn check_suspects(&self) {
+        let now = time::get_time();
+        let timeout = self.suspicion_timeout();
+        let expired = self.members.select(|member| {
+            member.state == MemberState::Suspect && now - member.state_change > timeout
+        });
+
+        for member in expired.into_iter() {
+            let ltime = self.clock.increment();
+            self.dead_node(member.inc, member.name, self.config.name.clone(), ltime);
+        }
+    }
+
+    ///   suspicion_timeout = suspicion_mult * log(N+1) * probe_interval
+    fn suspicion_timeout(&self) -> Duration {
+        let members_len = self.members.len();
+        let scale = ((members_len + 1) as f64).log10().max(1.0);
+        let timeout = self.config.suspicion_mult as f64 * scale
+                      * self.config.probe_interval.num_milliseconds() as f64;
+        Duration::milliseconds(timeout as i64)
+    }
+
+    fn queue_broadcast(&self, name: String, msg: Message) {
+        match self.config.wire_format.codec_for(&msg).encode(&msg) {
+            Ok(buf) => self.broadcasts.lock().queue(name, buf),
+            Err(e) => error!("Failed to encode message. Err: {}", e),
+        }
+    }
+
+    /// Tell the event delegate about a member which joined, left or was
+    /// updated, through the coalescer if there is one.
+    fn notify_member_event(&self, kind: MemberEventKind, member: Member) {
+        if let Some(ref coalescer) = self.member_coalescer {
+            coalescer.push(kind, member);
+        } else if let Some(ref delegate) = self.config.event_delegate {
+            notify_member_event(delegate, kind, &member);
+        }
+    }
+
+    /// Queue a broadcast about no member, like an event or a query.
+    fn queue_unique_broadcast(&self, msg: Message) {
+        match self.config.wire_format.codec_for(&msg).encode(&msg) {
+            Ok(buf) => self.broadcasts.lock().queue_unique(buf),
+            Err(e) => error!("Failed to encode message. Err: {}", e),
+        }
+    }
+
+    /// Used when a probe round is over. It will reap the dead members and
+    /// start the next round in a new random order.
+    fn reset_members(&self) {
+        let now = time::get_time();
+        let mut tombstones = self.tombstones.lock();
+
+        // A member may come back between selecting and removing it, so it is
+        // checked again when it is removed
+        let reapable = self.members.select(|member| self.is_reapable(member, now));
+        for candidate in reapable.into_iter() {
+            let member = match self.members.remove_if(candidate.name.as_slice(),
+                                                      |member| self.is_reapable(member, now)) {
+                Some(member) => member,
+                None => continue,
+            };
+
+            info!("Reaping {}", member);
+            self.latencies.remove(member.name.as_slice());
+            self.coordinates.remove(member.name.as_slice());
+            tombstones.insert(member.name.clone(), Tombstone {
+                member: member,
+                reaped: now,
+            });
+        }
+
+        self.members.reset_probe_order();
+
+        let expired: Vec<String> = tombstones.iter().filter(|&(_, tombstone)| {
+            now - tombstone.reaped > self.config.tombstone_retention
+        }).map(|(name, _)| name.clone()).collect();
+        for name in expired.iter() {
+            tombstones.remove(name);
+        }
+    }
+
+    /// Whether `member` has been dead or gone for `dead_reap_interval`.
+    fn is_reapable(&self, member: &Member, now: Timespec) -> bool {
+        let gone = member.state == MemberState::Dead || member.state == MemberState::Left;
+        gone && now - member.state_change > self.config.dead_reap_interval
+    }
+
+    /// Another node claims our name. Ask the other members which address
+    /// they know for it, and shut down if the majority believes the other
+    /// node.
+    fn resolve_conflict(meta: &Arc<MembershipMeta>, gossip: &Gossip) {
+        if !meta.config.resolve_name_conflicts
+           || meta.resolving_conflict.compare_and_swap(false, true, Ordering::Relaxed) {
+            return;
+        }
+
+        let meta = meta.clone();
+        let mut gossip = gossip.clone();
+        Thread::spawn(move || {
+            let name = meta.config.name.clone();
+            let peers = meta.members.select(|member| {
+                member.name != name && member.state == MemberState::Alive
+            });
+
+            let (tx, rx) = channel();
+            let mut queried = 0u;
+            for peer in peers.iter() {
+                let seq = meta.next_seq();
+                meta.acks.register(seq, meta.config.probe_timeout, box ConflictHandler {
+                    answers: tx.clone(),
+                });
+                match gossip.conflict_query(seq, name.clone(), peer.addr) {
+                    Ok(()) => queried += 1,
+                    Err(e) => {
+                        meta.acks.cancel(seq);
+                        error!("Failed to query {} about {}. Err: {}", peer, name, e);
+                    },
+                }
+            }
+
+            // Every handler answers, if only with a timeout
+            let mut votes: HashMap<SocketAddr, uint> = HashMap::new();
+            let mut answers = 0u;
+            for _ in range(0, queried) {
+                if let Some(addr) = rx.recv() {
+                    let count = votes.get(&addr).map(|v| *v).unwrap_or(0);
+                    votes.insert(addr, count + 1);
+                    answers += 1;
+                }
+            }
+
+            let ours = votes.get(&meta.advertise_addr).map(|v| *v).unwrap_or(0);
+            info!("Name conflict on {}: {} of {} members agree with us", name, ours, answers);
+
+            // Only give up the name to another claimant which a strict
+            // majority agrees with, a tie keeps us running
+            let winner = votes.iter()
+                              .filter(|&(addr, _)| *addr != meta.advertise_addr)
+                              .max_by(|&(_, count)| *count)
+                              .map(|(addr, count)| (*addr, *count));
+            match winner {
+                Some((addr, count)) if count * 2 > answers => {
+                    let winner = Member {
+                        name: name.clone(),
+                        addr: addr,
+                        state: MemberState::Alive,
+                        inc: 0,
+                        state_change: time::get_time(),
+                        tags: BTreeMap::new(),
+                        ltime: 0,
+                    };
+                    warn!("Lost the name conflict to {}, shutting down", winner.addr);
+                    meta.stop();
+                    if let Some(ref delegate) = meta.config.conflict_delegate {
+                        delegate.notify_conflict_lost(&winner);
+                    }
+                },
+                _ => {},
+            }
+
+            meta.resolving_conflict.store(false, Ordering::Relaxed);
+        }).detach();
+    }
+
+    /// Returns what we attach to our acks.
+    fn ack_payload(&self) -> AckPayload {
+        let coord = if self.config.enable_coordinates {
+            Some(self.coordinates.local())
+        } else {
+            None
+        };
+
+        let data = match self.config.ping_delegate {
+            Some(ref delegate) => {
+                let data = delegate.ack_payload();
+                if data.len() > MAX_PING_PAYLOAD_LEN {
+                    error!("Dropped ack payload of {} bytes, at most {} are allowed",
+                           data.len(), MAX_PING_PAYLOAD_LEN);
+                    Vec::new()
+                } else {
+                    data
+                }
+            },
+            None => Vec::new(),
+        };
+
+        AckPayload {
+            coord: coord,
+            data: data,
+        }
+    }
+
+    fn is_shutdown(&self) -> bool {
+        self.shutdown.load(Ordering::Relaxed)
+    }
+
+    /// Stop every thread of the local node.
+    fn stop(&self) {
+        self.shutdown.store(true, Ordering::Relaxed);
+        self.acks.stop();
+    }
+
+    /// Returns the answer to a node joining through us, which we accepted
+    /// with incarnation `inc`.
+    fn join_answer(&self, inc: u32) -> JoinAnswer {
+        let ltime = match self.members.get(self.config.name.as_slice()) {
+            Some(member) => member.read().ltime,
+            None => self.clock.time(),
+        };
+        let msg = Message::Alive {
+            inc: *self.inc.lock(),
+            name: self.config.name.clone(),
+            addr: self.advertise_addr,
+            tags: self.tags.lock().clone(),
+            ltime: ltime,
+        };
+        let alive = match self.config.wire_format.codec().encode(&msg) {
+            Ok(buf) => buf,
+            Err(e) => {
+                error!("Failed to encode our alive message. Err: {}", e);
+                Vec::new()
+            },
+        };
+
+        JoinAnswer {
+            alive: alive,
+            inc: inc,
+            event_ltime: self.event_clock.time(),
+            query_ltime: self.query_clock.time(),
+        }
+    }
+
+    fn next_seq(&self) -> u32 {
         let mut seq = self.seq.lock();
         (*seq) += 1;
         *seq
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+    use std::io::net::ip::{
+        Ipv4Addr,
+        Ipv6Addr,
+        SocketAddr,
+    };
+    use std::sync::{
+        Arc,
+        Mutex,
+    };
+    use std::time::Duration;
+
+    use time;
+
+    use codec::WireFormat;
+    use config;
+
+    use delegate::EventDelegate;
+
+    use event::MemberEventKind;
+
+    use member::{
+        Member,
+        MemberState,
+    };
+
+    use super::{
+        AliveOrigin,
+        Membership,
+        MembershipMeta,
+        Tombstone,
+    };
+
+    fn addr() -> SocketAddr {
+        SocketAddr {
+            ip: Ipv4Addr(127, 0, 0, 1),
+            port: 0,
+        }
+    }
+
+    /// Passes on the member events it is told about.
+    struct EventRecorder {
+        events: Mutex<Sender<(MemberEventKind, String)>>,
+    }
+
+    impl EventDelegate for EventRecorder {
+        fn notify_join(&self, member: &Member) {
+            self.events.lock().send((MemberEventKind::Join, member.name.clone()));
+        }
+
+        fn notify_leave(&self, member: &Member) {
+            self.events.lock().send((MemberEventKind::Leave, member.name.clone()));
+        }
+
+        fn notify_update(&self, member: &Member) {
+            self.events.lock().send((MemberEventKind::Update, member.name.clone()));
+        }
+    }
+
+    /// A node named `local`, which is not started.
+    fn membership() -> Membership {
+        let mut config = config::lan("local".to_string());
+        config.bind_addr = addr();
+        Membership::bind(config).unwrap()
+    }
+
+    fn add_member(meta: &MembershipMeta, name: &str) {
+        let _ = meta.alive_node(0, name.to_string(), addr(), BTreeMap::new(), 0,
+                               AliveOrigin::Gossip);
+    }
+
+    /// Another node, on the same host.
+    fn other_addr() -> SocketAddr {
+        SocketAddr {
+            ip: Ipv4Addr(127, 0, 0, 1),
+            port: 7946,
+        }
+    }
+
+    /// Reap `name` as if it just died at incarnation `inc` and Lamport time
+    /// `ltime`.
+    fn bury(meta: &MembershipMeta, name: &str, inc: u32, ltime: u64) {
+        let now = time::get_time();
+        meta.clock.witness(ltime);
+        meta.tombstones.lock().insert(name.to_string(), Tombstone {
+            member: Member {
+                name: name.to_string(),
+                addr: addr(),
+                state: MemberState::Dead,
+                inc: inc,
+                state_change: now,
+                tags: BTreeMap::new(),
+                ltime: ltime,
+            },
+            reaped: now,
+        });
+    }
+
+    /// A node which just started joins as `name` at `addr`, from `from`.
+    fn join(meta: &MembershipMeta, name: &str, addr: SocketAddr,
+            from: SocketAddr) -> Result<u32, String> {
+        meta.handle_join(0, name.to_string(), addr, BTreeMap::new(), 1, from)
+    }
+
+    fn kill_member(meta: &MembershipMeta, name: &str) {
+        if let Some(member) = meta.members.get(name) {
+            member.write().state = MemberState::Dead;
+        }
+    }
+
+    /// Returns the names of the next `count` members to probe.
+    fn probe_names(meta: &MembershipMeta, count: uint) -> Vec<String> {
+        let mut names: Vec<String> = range(0, count).map(|_| {
+            meta.next_to_probe().unwrap().name
+        }).collect();
+        names.sort();
+        names
+    }
+
+    fn names(names: &[&str]) -> Vec<String> {
+        names.iter().map(|name| name.to_string()).collect()
+    }
+
+    #[test]
+    fn probe_timeout_must_be_shorter_than_probe_interval() {
+        let mut config = config::lan("local".to_string());
+        config.bind_addr = addr();
+        config.probe_timeout = config.probe_interval;
+        assert!(Membership::bind(config.clone()).is_err());
+
+        config.probe_timeout = Duration::zero();
+        assert!(Membership::bind(config.clone()).is_err());
+
+        // Unless probing is disabled
+        config.probe_interval = Duration::zero();
+        assert!(Membership::bind(config).is_ok());
+    }
+
+    #[test]
+    fn ipv6_is_only_advertised_with_msgpack() {
+        let mut config = config::lan("local".to_string());
+        config.bind_addr = addr();
+        config.advertise_addr = Some(SocketAddr {
+            ip: Ipv6Addr(0xfd00, 0, 0, 0, 0, 0, 0, 1),
+            port: 7946,
+        });
+        assert!(Membership::bind(config.clone()).is_err());
+
+        config.wire_format = WireFormat::MessagePack;
+        assert!(Membership::bind(config).is_ok());
+    }
+
+    #[test]
+    fn every_member_is_probed_once_per_round() {
+        let membership = membership();
+        let meta = &*membership.meta;
+        for name in ["a", "b", "c", "d"].iter() {
+            add_member(meta, *name);
+        }
+
+        assert_eq!(probe_names(meta, 4), names(&["a", "b", "c", "d"]));
+        // The next round is shuffled, but still probes everybody once
+        assert_eq!(probe_names(meta, 4), names(&["a", "b", "c", "d"]));
+    }
+
+    #[test]
+    fn local_and_dead_members_are_skipped() {
+        let membership = membership();
+        let meta = &*membership.meta;
+        for name in ["local", "a", "b", "dead"].iter() {
+            add_member(meta, *name);
+        }
+        kill_member(meta, "dead");
+
+        assert_eq!(probe_names(meta, 2), names(&["a", "b"]));
+        assert_eq!(probe_names(meta, 2), names(&["a", "b"]));
+    }
+
+    #[test]
+    fn member_added_during_a_round_is_probed_in_it() {
+        let membership = membership();
+        let meta = &*membership.meta;
+        for name in ["a", "b", "c"].iter() {
+            add_member(meta, *name);
+        }
+
+        let first = meta.next_to_probe().unwrap().name;
+        add_member(meta, "d");
+        let mut probed = probe_names(meta, 3);
+        probed.push(first);
+        probed.sort();
+        assert_eq!(probed, names(&["a", "b", "c", "d"]));
+    }
+
+    #[test]
+    fn nobody_to_probe() {
+        let membership = membership();
+        let meta = &*membership.meta;
+        assert!(meta.next_to_probe().is_none());
+
+        add_member(meta, "local");
+        add_member(meta, "dead");
+        kill_member(meta, "dead");
+        assert!(meta.next_to_probe().is_none());
+        assert!(meta.next_to_probe().is_none());
+    }
+
+    #[test]
+    fn restarted_node_is_vouched_for_over_its_tombstone() {
+        let membership = membership();
+        let meta = &*membership.meta;
+        bury(meta, "a", 5, 100);
+
+        assert_eq!(join(meta, "a", addr(), addr()), Ok(6));
+        let member = meta.members.get("a").unwrap().read().clone();
+        assert_eq!(member.state, MemberState::Alive);
+        assert_eq!(member.inc, 6);
+        assert!(member.ltime > 100);
+        assert!(meta.tombstones.lock().get(&"a".to_string()).is_none());
+    }
+
+    #[test]
+    fn restarted_node_is_vouched_for_while_alive() {
+        let membership = membership();
+        let meta = &*membership.meta;
+        add_member(meta, "a");
+
+        assert_eq!(join(meta, "a", addr(), addr()), Ok(1));
+        assert_eq!(meta.members.get("a").unwrap().read().inc, 1);
+    }
+
+    #[test]
+    fn join_over_the_tombstone_of_another_node_is_rejected() {
+        let membership = membership();
+        let meta = &*membership.meta;
+        bury(meta, "a", 5, 100);
+
+        assert!(join(meta, "a", other_addr(), other_addr()).is_err());
+        assert!(meta.members.get("a").is_none());
+        assert!(meta.tombstones.lock().get(&"a".to_string()).is_some());
+    }
+
+    #[test]
+    fn stale_gossip_over_a_tombstone_is_ignored() {
+        let membership = membership();
+        let meta = &*membership.meta;
+        bury(meta, "a", 5, 100);
+
+        assert_eq!(meta.alive_node(5, "a".to_string(), addr(), BTreeMap::new(), 50,
+                                   AliveOrigin::Gossip), Ok(None));
+        assert!(meta.members.get("a").is_none());
+        assert_eq!(meta.alive_node(6, "a".to_string(), addr(), BTreeMap::new(), 0,
+                                   AliveOrigin::Gossip), Ok(Some(6)));
+    }
+
+    #[test]
+    fn spoofed_join_is_rejected() {
+        let membership = membership();
+        let meta = &*membership.meta;
+
+        assert!(join(meta, "a", addr(), other_addr()).is_err());
+        assert!(meta.members.get("a").is_none());
+
+        bury(meta, "b", 5, 100);
+        assert!(join(meta, "b", addr(), other_addr()).is_err());
+        assert!(meta.members.get("b").is_none());
+    }
+
+    #[test]
+    fn suspected_member_refuting_reports_nothing() {
+        let (tx, rx) = channel();
+        let mut config = config::lan("local".to_string());
+        config.bind_addr = addr();
+        config.event_delegate = Some(Arc::new(box EventRecorder {
+            events: Mutex::new(tx),
+        } as Box<EventDelegate + Send + Sync>));
+        // Long enough that all events end up in a single batch
+        config.member_event_quiet_period = Duration::hours(1);
+        config.member_event_max_delay = Duration::hours(1);
+        let membership = Membership::bind(config).unwrap();
+
+        {
+            let meta = &*membership.meta;
+            add_member(meta, "a");
+            meta.suspect_node(0, "a".to_string(), "local".to_string());
+            assert_eq!(meta.members.get("a").unwrap().read().state, MemberState::Suspect);
+            assert_eq!(meta.alive_node(1, "a".to_string(), addr(), BTreeMap::new(), 0,
+                                       AliveOrigin::Gossip), Ok(Some(1)));
+            assert_eq!(meta.members.get("a").unwrap().read().state, MemberState::Alive);
+        }
+
+        // The coalescer delivers its batch once it is gone, and the recorder
+        // is dropped once every coalescer thread ended
+        drop(membership);
+        let events: Vec<(MemberEventKind, String)> = rx.iter().collect();
+        assert_eq!(events, vec![(MemberEventKind::Join, "a".to_string())]);
+    }
+}
@@ -1,10 +1,24 @@
-use std::io::{
-    IoError,
-    IoErrorKind,
-    IoResult,
-    Writer,
+use std::collections::BTreeMap;
+use std::error::FromError;
+use std::io::Writer;
+use std::io::net::ip::{
+    Ipv4Addr,
+    SocketAddr,
+};
+
+use codec;
+
+use filter::Filter;
+
+use error::{
+    Error,
+    ErosionResult,
+};
+
+use input::{
+    read_bounded,
+    Input,
 };
-use std::io::net::ip::SocketAddr;
 
 #[repr(u8)]
 #[deriving(Copy, FromPrimitive)]
@@ -12,6 +26,18 @@ pub enum MessageType {
     Ping = 0,
     IndirectPing,
     Ack,
+    Nack,
+    Suspect,
+    Alive,
+    Dead,
+    Compound,
+    ConflictQuery,
+    Join,
+    Reject,
+    Query,
+    QueryResponse,
+    Relay,
+    UserEvent,
 }
 
 #[deriving(Show)]
@@ -29,81 +55,377 @@ pub enum Message {
         name: String,
     },
 
+    // The payload is opaque to the transport and handed to the ack handler.
     Ack {
         seq: u32,
+        payload: Vec<u8>,
+    },
+
+    // Sent by an indirect probe helper when its own ping to the target timed
+    // out, so the originator can tell a dead target from a broken helper.
+    Nack {
+        seq: u32,
+    },
+
+    Suspect {
+        inc: u32,
+        name: String,
+        from: String,
+    },
+
+    // The tags of a member change along with its incarnation number.
+    // `ltime` is the Lamport time of the membership change, set by the node
+    // which made it, like the Dead one.
+    Alive {
+        inc: u32,
+        name: String,
+        addr: SocketAddr,
+        tags: BTreeMap<String, String>,
+        ltime: u64,
+    },
+
+    // A dead message sent by the node itself (`from == name`) means the node
+    // has left the cluster.
+    Dead {
+        inc: u32,
+        name: String,
+        from: String,
+        ltime: u64,
+    },
+
+    // Several messages packed into a single packet, used to piggyback
+    // broadcasts.
+    Compound {
+        msgs: Vec<Message>,
+    },
+
+    // Asks which address the recipient knows for the member `name`. It is
+    // answered with an ack carrying the address, or nothing if unknown.
+    ConflictQuery {
+        seq: u32,
+        name: String,
+    },
+
+    // Sent by a node joining through the recipient, which answers with an
+    // ack if it admits the node, or a reject otherwise.
+    Join {
+        seq: u32,
+        inc: u32,
+        name: String,
+        addr: SocketAddr,
+        tags: BTreeMap<String, String>,
+        ltime: u64,
+    },
+
+    Reject {
+        seq: u32,
+        reason: String,
+    },
+
+    // Disseminated by gossip. Every member matched by `filter` acks it and
+    // answers with the response of its query delegate, both sent to `addr`.
+    // The query messages are only encoded as MessagePack, as the filter
+    // doesn't fit the legacy layout.
+    Query {
+        id: u32,
+        ltime: u64,
+        from: String,
+        addr: SocketAddr,
+        name: String,
+        payload: Vec<u8>,
+        filter: Filter,
+        relay_factor: u8,
+    },
+
+    QueryResponse {
+        id: u32,
+        from: String,
+        ack: bool,
+        payload: Vec<u8>,
+    },
+
+    // An encoded message the recipient forwards as is to `addr`, used when
+    // the direct path to `addr` may be broken.
+    Relay {
+        addr: SocketAddr,
+        msg: Vec<u8>,
+    },
+
+    // Disseminated by gossip and handed to the event delegate of every
+    // member.
+    UserEvent {
+        ltime: u64,
+        name: String,
+        payload: Vec<u8>,
     },
 
-    Suspect,
-    Alive,
-    Dead,
     None,
 }
 
 impl Message {
-    pub fn write<W: Writer>(&self, writer: &mut W) -> IoResult<()> {
+    pub fn write<W: Writer>(&self, writer: &mut W) -> ErosionResult<()> {
         match self {
             &Message::Ping {
                 ref seq,
                 ref name,
             } => {
                 if let Err(e) = writer.write_u8(MessageType::Ping as u8) {
-                    return Err(e);
+                    return Err(FromError::from_error(e));
                 }
                 if let Err(e) = writer.write_be_u32(*seq) {
-                    return Err(e);
+                    return Err(FromError::from_error(e));
                 }
-                if let Err(e) = write_str(writer, name.as_slice()) {
-                    return Err(e);
+                if let Err(e) = write_str(writer, "Name", name.as_slice(), MAX_NAME_LEN) {
+                    return Err(FromError::from_error(e));
+                }
+                Ok(())
+            },
+
+            &Message::IndirectPing {
+                ref addr,
+                ref seq,
+                ref name,
+            } => {
+                if let Err(e) = writer.write_u8(MessageType::IndirectPing as u8) {
+                    return Err(FromError::from_error(e));
+                }
+                if let Err(e) = write_addr(writer, addr) {
+                    return Err(FromError::from_error(e));
+                }
+                if let Err(e) = writer.write_be_u32(*seq) {
+                    return Err(FromError::from_error(e));
+                }
+                if let Err(e) = write_str(writer, "Name", name.as_slice(), MAX_NAME_LEN) {
+                    return Err(FromError::from_error(e));
                 }
                 Ok(())
             },
 
             &Message::Ack {
                 ref seq,
+                ref payload,
             } => {
                 if let Err(e) = writer.write_u8(MessageType::Ack as u8) {
+                    return Err(FromError::from_error(e));
+                }
+                if let Err(e) = writer.write_be_u32(*seq) {
+                    return Err(FromError::from_error(e));
+                }
+                if let Err(e) = write_bytes(writer, "Payload", payload.as_slice(),
+                                            MAX_PAYLOAD_LEN) {
+                    return Err(FromError::from_error(e));
+                }
+                Ok(())
+            },
+
+            &Message::Nack {
+                ref seq,
+            } => {
+                if let Err(e) = writer.write_u8(MessageType::Nack as u8) {
+                    return Err(FromError::from_error(e));
+                }
+                if let Err(e) = writer.write_be_u32(*seq) {
+                    return Err(FromError::from_error(e));
+                }
+                Ok(())
+            },
+
+            &Message::Suspect {
+                ref inc,
+                ref name,
+                ref from,
+            } => {
+                if let Err(e) = writer.write_u8(MessageType::Suspect as u8) {
+                    return Err(FromError::from_error(e));
+                }
+                if let Err(e) = writer.write_be_u32(*inc) {
+                    return Err(FromError::from_error(e));
+                }
+                if let Err(e) = write_str(writer, "Name", name.as_slice(), MAX_NAME_LEN) {
+                    return Err(FromError::from_error(e));
+                }
+                if let Err(e) = write_str(writer, "Name", from.as_slice(), MAX_NAME_LEN) {
+                    return Err(FromError::from_error(e));
+                }
+                Ok(())
+            },
+
+            &Message::Alive {
+                ref inc,
+                ref name,
+                ref addr,
+                ref tags,
+                ref ltime,
+            } => {
+                if let Err(e) = writer.write_u8(MessageType::Alive as u8) {
+                    return Err(FromError::from_error(e));
+                }
+                if let Err(e) = writer.write_be_u32(*inc) {
+                    return Err(FromError::from_error(e));
+                }
+                if let Err(e) = write_str(writer, "Name", name.as_slice(), MAX_NAME_LEN) {
+                    return Err(FromError::from_error(e));
+                }
+                if let Err(e) = write_addr(writer, addr) {
+                    return Err(FromError::from_error(e));
+                }
+                if let Err(e) = write_tags(writer, tags) {
                     return Err(e);
                 }
+                if let Err(e) = writer.write_be_u64(*ltime) {
+                    return Err(FromError::from_error(e));
+                }
+                Ok(())
+            },
+
+            &Message::Dead {
+                ref inc,
+                ref name,
+                ref from,
+                ref ltime,
+            } => {
+                if let Err(e) = writer.write_u8(MessageType::Dead as u8) {
+                    return Err(FromError::from_error(e));
+                }
+                if let Err(e) = writer.write_be_u32(*inc) {
+                    return Err(FromError::from_error(e));
+                }
+                if let Err(e) = write_str(writer, "Name", name.as_slice(), MAX_NAME_LEN) {
+                    return Err(FromError::from_error(e));
+                }
+                if let Err(e) = write_str(writer, "Name", from.as_slice(), MAX_NAME_LEN) {
+                    return Err(FromError::from_error(e));
+                }
+                if let Err(e) = writer.write_be_u64(*ltime) {
+                    return Err(FromError::from_error(e));
+                }
+                Ok(())
+            },
+
+            &Message::Compound {
+                ref msgs,
+            } => {
+                let mut parts = Vec::new();
+                for msg in msgs.iter() {
+                    let mut part = Vec::new();
+                    if let Err(e) = msg.write(&mut part) {
+                        return Err(FromError::from_error(e));
+                    }
+                    parts.push(part);
+                }
+                write_compound(writer, parts.as_slice())
+            },
+
+            &Message::ConflictQuery {
+                ref seq,
+                ref name,
+            } => {
+                if let Err(e) = writer.write_u8(MessageType::ConflictQuery as u8) {
+                    return Err(FromError::from_error(e));
+                }
+                if let Err(e) = writer.write_be_u32(*seq) {
+                    return Err(FromError::from_error(e));
+                }
+                if let Err(e) = write_str(writer, "Name", name.as_slice(), MAX_NAME_LEN) {
+                    return Err(FromError::from_error(e));
+                }
+                Ok(())
+            },
+
+            &Message::Join {
+                ref seq,
+                ref inc,
+                ref name,
+                ref addr,
+                ref tags,
+                ref ltime,
+            } => {
+                if let Err(e) = writer.write_u8(MessageType::Join as u8) {
+                    return Err(FromError::from_error(e));
+                }
                 if let Err(e) = writer.write_be_u32(*seq) {
+                    return Err(FromError::from_error(e));
+                }
+                if let Err(e) = writer.write_be_u32(*inc) {
+                    return Err(FromError::from_error(e));
+                }
+                if let Err(e) = write_str(writer, "Name", name.as_slice(), MAX_NAME_LEN) {
+                    return Err(FromError::from_error(e));
+                }
+                if let Err(e) = write_addr(writer, addr) {
+                    return Err(FromError::from_error(e));
+                }
+                if let Err(e) = write_tags(writer, tags) {
                     return Err(e);
                 }
+                if let Err(e) = writer.write_be_u64(*ltime) {
+                    return Err(FromError::from_error(e));
+                }
                 Ok(())
             },
 
-            _ => {
-                Err(IoError {
-                    kind: IoErrorKind::IoUnavailable,
-                    desc: "Message not supported",
-                    detail: None,
-                })
-            }
+            &Message::Reject {
+                ref seq,
+                ref reason,
+            } => {
+                if let Err(e) = writer.write_u8(MessageType::Reject as u8) {
+                    return Err(FromError::from_error(e));
+                }
+                if let Err(e) = writer.write_be_u32(*seq) {
+                    return Err(FromError::from_error(e));
+                }
+                if let Err(e) = write_str(writer, "Reason", reason.as_slice(), MAX_REASON_LEN) {
+                    return Err(FromError::from_error(e));
+                }
+                Ok(())
+            },
+
+            &Message::UserEvent {
+                ref ltime,
+                ref name,
+                ref payload,
+            } => {
+                if let Err(e) = writer.write_u8(MessageType::UserEvent as u8) {
+                    return Err(FromError::from_error(e));
+                }
+                if let Err(e) = writer.write_be_u64(*ltime) {
+                    return Err(FromError::from_error(e));
+                }
+                if let Err(e) = write_str(writer, "Name", name.as_slice(), MAX_NAME_LEN) {
+                    return Err(FromError::from_error(e));
+                }
+                if let Err(e) = write_bytes(writer, "Payload", payload.as_slice(),
+                                            MAX_PAYLOAD_LEN) {
+                    return Err(FromError::from_error(e));
+                }
+                Ok(())
+            },
+
+            _ => Err(Error::UnsupportedMessage),
         }
     }
 
-    pub fn read<R: Reader>(reader: &mut R) -> IoResult<Message> {
+    pub fn read<R: Input>(reader: &mut R) -> ErosionResult<Message> {
         let result = reader.read_u8();
         if let Err(e) = result {
-            return Err(e);
+            return Err(FromError::from_error(e));
         }
-        let message_type: Option<MessageType> = FromPrimitive::from_u8(result.unwrap());
+        let type_byte = result.unwrap();
+        let message_type: Option<MessageType> = FromPrimitive::from_u8(type_byte);
         if message_type.is_none() {
-            return Err(IoError {
-                    kind: IoErrorKind::IoUnavailable,
-                    desc: "Message not supported",
-                    detail: None,
-            });
+            return Err(Error::UnknownMessageType(type_byte));
         }
 
         match message_type.unwrap() {
             MessageType::Ping => {
                 let seq = reader.read_be_u32();
                 if let Err(e) = seq {
-                    return Err(e);
+                    return Err(FromError::from_error(e));
                 }
 
-                let name = read_str(reader);
+                let name = read_str(reader, "Name", MAX_NAME_LEN);
                 if let Err(e) = name {
-                    return Err(e);
+                    return Err(FromError::from_error(e));
                 }
 
                 Ok(Message::Ping {
@@ -112,59 +434,782 @@ impl Message {
                 })
             },
 
+            MessageType::IndirectPing => {
+                let addr = read_addr(reader);
+                if let Err(e) = addr {
+                    return Err(FromError::from_error(e));
+                }
+
+                let seq = reader.read_be_u32();
+                if let Err(e) = seq {
+                    return Err(FromError::from_error(e));
+                }
+
+                let name = read_str(reader, "Name", MAX_NAME_LEN);
+                if let Err(e) = name {
+                    return Err(FromError::from_error(e));
+                }
+
+                Ok(Message::IndirectPing {
+                    addr: addr.unwrap(),
+                    seq: seq.unwrap(),
+                    name: name.unwrap(),
+                })
+            },
+
             MessageType::Ack => {
                 let seq = reader.read_be_u32();
                 if let Err(e) = seq {
-                    return Err(e);
+                    return Err(FromError::from_error(e));
+                }
+
+                let payload = read_bytes(reader, "Payload", MAX_PAYLOAD_LEN);
+                if let Err(e) = payload {
+                    return Err(FromError::from_error(e));
                 }
 
                 Ok(Message::Ack {
                     seq: seq.unwrap(),
+                    payload: payload.unwrap(),
+                })
+            },
+
+            MessageType::Nack => {
+                let seq = reader.read_be_u32();
+                if let Err(e) = seq {
+                    return Err(FromError::from_error(e));
+                }
+
+                Ok(Message::Nack {
+                    seq: seq.unwrap(),
+                })
+            },
+
+            MessageType::Suspect => {
+                let inc = reader.read_be_u32();
+                if let Err(e) = inc {
+                    return Err(FromError::from_error(e));
+                }
+
+                let name = read_str(reader, "Name", MAX_NAME_LEN);
+                if let Err(e) = name {
+                    return Err(FromError::from_error(e));
+                }
+
+                let from = read_str(reader, "Name", MAX_NAME_LEN);
+                if let Err(e) = from {
+                    return Err(FromError::from_error(e));
+                }
+
+                Ok(Message::Suspect {
+                    inc: inc.unwrap(),
+                    name: name.unwrap(),
+                    from: from.unwrap(),
                 })
             },
 
-            _ => Ok(Message::None)
+            MessageType::Alive => {
+                let inc = reader.read_be_u32();
+                if let Err(e) = inc {
+                    return Err(FromError::from_error(e));
+                }
+
+                let name = read_str(reader, "Name", MAX_NAME_LEN);
+                if let Err(e) = name {
+                    return Err(FromError::from_error(e));
+                }
+
+                let addr = read_addr(reader);
+                if let Err(e) = addr {
+                    return Err(FromError::from_error(e));
+                }
+
+                let tags = read_tags(reader);
+                if let Err(e) = tags {
+                    return Err(e);
+                }
+
+                let ltime = read_ltime(reader);
+                if let Err(e) = ltime {
+                    return Err(e);
+                }
+
+                Ok(Message::Alive {
+                    inc: inc.unwrap(),
+                    name: name.unwrap(),
+                    addr: addr.unwrap(),
+                    tags: tags.unwrap(),
+                    ltime: ltime.unwrap(),
+                })
+            },
+
+            MessageType::Dead => {
+                let inc = reader.read_be_u32();
+                if let Err(e) = inc {
+                    return Err(FromError::from_error(e));
+                }
+
+                let name = read_str(reader, "Name", MAX_NAME_LEN);
+                if let Err(e) = name {
+                    return Err(FromError::from_error(e));
+                }
+
+                let from = read_str(reader, "Name", MAX_NAME_LEN);
+                if let Err(e) = from {
+                    return Err(FromError::from_error(e));
+                }
+
+                let ltime = read_ltime(reader);
+                if let Err(e) = ltime {
+                    return Err(e);
+                }
+
+                Ok(Message::Dead {
+                    inc: inc.unwrap(),
+                    name: name.unwrap(),
+                    from: from.unwrap(),
+                    ltime: ltime.unwrap(),
+                })
+            },
+
+            MessageType::Compound => {
+                let count = reader.read_u8();
+                if let Err(e) = count {
+                    return Err(FromError::from_error(e));
+                }
+
+                let mut msgs = Vec::new();
+                for _ in range(0, count.unwrap()) {
+                    let len = reader.read_be_u16();
+                    if let Err(e) = len {
+                        return Err(FromError::from_error(e));
+                    }
+
+                    let part = read_bounded(reader, len.unwrap() as uint);
+                    if let Err(e) = part {
+                        return Err(e);
+                    }
+
+                    // A compound part may not be a compound itself, otherwise a
+                    // packet of nested headers recurses once per 4 bytes.
+                    let part = part.unwrap();
+                    if part.first() == Some(&(MessageType::Compound as u8)) {
+                        return Err(Error::InvalidField("compound message part"));
+                    }
+
+                    // Parts are encoded on their own, in either wire format,
+                    // so a MessagePack part may still be a compound
+                    match codec::decode(part.as_slice()) {
+                        Ok(Message::Compound { .. }) => {
+                            return Err(Error::InvalidField("compound message part"));
+                        },
+                        Ok(msg) => msgs.push(msg),
+                        Err(e) => return Err(e),
+                    }
+                }
+
+                Ok(Message::Compound {
+                    msgs: msgs,
+                })
+            },
+
+            MessageType::ConflictQuery => {
+                let seq = reader.read_be_u32();
+                if let Err(e) = seq {
+                    return Err(FromError::from_error(e));
+                }
+
+                let name = read_str(reader, "Name", MAX_NAME_LEN);
+                if let Err(e) = name {
+                    return Err(FromError::from_error(e));
+                }
+
+                Ok(Message::ConflictQuery {
+                    seq: seq.unwrap(),
+                    name: name.unwrap(),
+                })
+            },
+
+            MessageType::Join => {
+                let seq = reader.read_be_u32();
+                if let Err(e) = seq {
+                    return Err(FromError::from_error(e));
+                }
+
+                let inc = reader.read_be_u32();
+                if let Err(e) = inc {
+                    return Err(FromError::from_error(e));
+                }
+
+                let name = read_str(reader, "Name", MAX_NAME_LEN);
+                if let Err(e) = name {
+                    return Err(FromError::from_error(e));
+                }
+
+                let addr = read_addr(reader);
+                if let Err(e) = addr {
+                    return Err(FromError::from_error(e));
+                }
+
+                let tags = read_tags(reader);
+                if let Err(e) = tags {
+                    return Err(e);
+                }
+
+                let ltime = read_ltime(reader);
+                if let Err(e) = ltime {
+                    return Err(e);
+                }
+
+                Ok(Message::Join {
+                    seq: seq.unwrap(),
+                    inc: inc.unwrap(),
+                    name: name.unwrap(),
+                    addr: addr.unwrap(),
+                    tags: tags.unwrap(),
+                    ltime: ltime.unwrap(),
+                })
+            },
+
+            MessageType::Reject => {
+                let seq = reader.read_be_u32();
+                if let Err(e) = seq {
+                    return Err(FromError::from_error(e));
+                }
+
+                let reason = read_str(reader, "Reason", MAX_REASON_LEN);
+                if let Err(e) = reason {
+                    return Err(FromError::from_error(e));
+                }
+
+                Ok(Message::Reject {
+                    seq: seq.unwrap(),
+                    reason: reason.unwrap(),
+                })
+            },
+
+            MessageType::Query | MessageType::QueryResponse | MessageType::Relay => {
+                Err(Error::UnsupportedMessage)
+            },
+
+            MessageType::UserEvent => {
+                let ltime = reader.read_be_u64();
+                if let Err(e) = ltime {
+                    return Err(FromError::from_error(e));
+                }
+
+                let name = read_str(reader, "Name", MAX_NAME_LEN);
+                if let Err(e) = name {
+                    return Err(FromError::from_error(e));
+                }
+
+                let payload = read_bytes(reader, "Payload", MAX_PAYLOAD_LEN);
+                if let Err(e) = payload {
+                    return Err(FromError::from_error(e));
+                }
+
+                Ok(Message::UserEvent {
+                    ltime: ltime.unwrap(),
+                    name: name.unwrap(),
+                    payload: payload.unwrap(),
+                })
+            },
         }
     }
 }
 
-fn write_str<W: Writer>(writer: &mut W, msg: &str) -> IoResult<()> {
-    let len = msg.len().to_u8();
-    if let None = len {
-        return Err(IoError {
-            kind: IoErrorKind::InvalidInput,
-            desc: "Message is too long",
-            detail: None,
+/// The longest node name, in bytes.
+pub const MAX_NAME_LEN: uint = 512;
+
+/// The longest ack payload, in bytes.
+pub const MAX_PAYLOAD_LEN: uint = 1024;
+
+/// The largest number of tags of a member.
+pub const MAX_TAGS: uint = 64;
+
+/// The longest tag key or value, in bytes.
+pub const MAX_TAG_LEN: uint = 512;
+
+/// The largest total length of the tag keys and values of a member, in
+/// bytes. It keeps the member's Alive message small enough for a packet and
+/// for the answer to a join, which rides in an ack payload.
+pub const MAX_TAGS_SIZE: uint = 512;
+
+/// The longest reason of a reject, in bytes.
+pub const MAX_REASON_LEN: uint = 512;
+
+/// The longest payload of a query or of its response, in bytes.
+pub const MAX_QUERY_PAYLOAD_LEN: uint = 8192;
+
+/// The number of bytes a compound message adds on top of its parts.
+pub const COMPOUND_HEADER_OVERHEAD: uint = 2;
+
+/// The largest number of parts in a compound message.
+pub const COMPOUND_MAX_PARTS: uint = 255;
+
+/// The number of bytes a compound message adds for each of its parts.
+pub const COMPOUND_PART_OVERHEAD: uint = 2;
+
+/// Write already encoded messages as a single compound message.
+pub fn write_compound<W: Writer>(writer: &mut W, parts: &[Vec<u8>]) -> ErosionResult<()> {
+    let count = parts.len().to_u8();
+    if let None = count {
+        return Err(Error::TooLong {
+            field: "Compound message",
+            len: parts.len(),
+            max: COMPOUND_MAX_PARTS,
+        });
+    }
+
+    if let Err(e) = writer.write_u8(MessageType::Compound as u8) {
+        return Err(FromError::from_error(e));
+    }
+    if let Err(e) = writer.write_u8(count.unwrap()) {
+        return Err(FromError::from_error(e));
+    }
+
+    for part in parts.iter() {
+        let len = part.len().to_u16();
+        if let None = len {
+            return Err(Error::TooLong {
+                field: "Compound message part",
+                len: part.len(),
+                max: 65535,
+            });
+        }
+        if let Err(e) = writer.write_be_u16(len.unwrap()) {
+            return Err(FromError::from_error(e));
+        }
+        if let Err(e) = writer.write(part.as_slice()) {
+            return Err(FromError::from_error(e));
+        }
+    }
+
+    Ok(())
+}
+
+/// Write `value` as a LEB128 varint: 7 bits per byte, least significant
+/// first, with the high bit set on every byte but the last.
+fn write_varint<W: Writer>(writer: &mut W, value: u64) -> ErosionResult<()> {
+    let mut value = value;
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        if value == 0 {
+            if let Err(e) = writer.write_u8(byte) {
+                return Err(FromError::from_error(e));
+            }
+            return Ok(());
+        }
+        if let Err(e) = writer.write_u8(byte | 0x80) {
+            return Err(FromError::from_error(e));
+        }
+    }
+}
+
+fn read_varint<R: Input>(reader: &mut R) -> ErosionResult<u64> {
+    let mut value = 0u64;
+    let mut shift = 0u;
+    loop {
+        let byte = reader.read_u8();
+        if let Err(e) = byte {
+            return Err(FromError::from_error(e));
+        }
+        let byte = byte.unwrap();
+
+        // A u64 takes at most 10 bytes
+        if shift >= 64 {
+            return Err(Error::InvalidField("varint"));
+        }
+        value |= ((byte & 0x7f) as u64) << shift;
+        shift += 7;
+
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+}
+
+/// Read a length prefix, rejecting it before anything is allocated if it
+/// is longer than `max`.
+fn read_len<R: Input>(reader: &mut R, field: &'static str, max: uint) -> ErosionResult<uint> {
+    let len = read_varint(reader);
+    if let Err(e) = len {
+        return Err(e);
+    }
+    let len = len.unwrap();
+
+    if len > max as u64 {
+        return Err(Error::TooLong {
+            field: field,
+            len: len.to_uint().unwrap_or(::std::uint::MAX),
+            max: max,
         });
     }
+    Ok(len as uint)
+}
+
+fn write_str<W: Writer>(writer: &mut W, field: &'static str, msg: &str,
+                        max: uint) -> ErosionResult<()> {
+    write_bytes(writer, field, msg.as_bytes(), max)
+}
 
-    if let Err(e) = writer.write_u8(len.unwrap()) {
+fn read_str<R: Input>(reader: &mut R, field: &'static str, max: uint) -> ErosionResult<String> {
+    let bytes = read_bytes(reader, field, max);
+    if let Err(e) = bytes {
         return Err(e);
     }
 
-    if let Err(e) = writer.write_str(msg) {
+    match String::from_utf8(bytes.unwrap()) {
+        Ok(msg) => Ok(msg),
+        Err(_) => Err(Error::InvalidUtf8),
+    }
+}
+
+fn write_bytes<W: Writer>(writer: &mut W, field: &'static str, bytes: &[u8],
+                          max: uint) -> ErosionResult<()> {
+    if bytes.len() > max {
+        return Err(Error::TooLong {
+            field: field,
+            len: bytes.len(),
+            max: max,
+        });
+    }
+
+    if let Err(e) = write_varint(writer, bytes.len() as u64) {
         return Err(e);
     }
 
+    if let Err(e) = writer.write(bytes) {
+        return Err(FromError::from_error(e));
+    }
+
     Ok(())
 }
 
-fn read_str<R: Reader>(reader: &mut R) -> IoResult<String> {
-    let len = reader.read_u8();
+fn read_bytes<R: Input>(reader: &mut R, field: &'static str, max: uint) -> ErosionResult<Vec<u8>> {
+    let len = read_len(reader, field, max);
     if let Err(e) = len {
         return Err(e);
     }
-    match reader.read_exact(len.unwrap() as uint) {
-        Ok(msg) => {
-            match String::from_utf8(msg) {
-                Ok(msg) => Ok(msg),
-                Err(_) => Err(IoError {
-                    kind: IoErrorKind::InvalidInput,
-                    desc: "Not a valid UTF8 string",
-                    detail: None,
-                }),
+
+    read_bounded(reader, len.unwrap())
+}
+
+/// Tags come last, so decoders which predate them ignore them.
+fn write_tags<W: Writer>(writer: &mut W, tags: &BTreeMap<String, String>) -> ErosionResult<()> {
+    if tags.len() > MAX_TAGS {
+        return Err(Error::TooLong {
+            field: "Tags",
+            len: tags.len(),
+            max: MAX_TAGS,
+        });
+    }
+
+    let size = tags_size(tags);
+    if size > MAX_TAGS_SIZE {
+        return Err(Error::TooLong {
+            field: "Tags",
+            len: size,
+            max: MAX_TAGS_SIZE,
+        });
+    }
+
+    if let Err(e) = write_varint(writer, tags.len() as u64) {
+        return Err(e);
+    }
+    for (key, value) in tags.iter() {
+        if let Err(e) = write_str(writer, "Tag", key.as_slice(), MAX_TAG_LEN) {
+            return Err(e);
+        }
+        if let Err(e) = write_str(writer, "Tag", value.as_slice(), MAX_TAG_LEN) {
+            return Err(e);
+        }
+    }
+    Ok(())
+}
+
+/// A message which ends before its tags comes from a node which predates
+/// them, and has none.
+fn read_tags<R: Input>(reader: &mut R) -> ErosionResult<BTreeMap<String, String>> {
+    let mut tags = BTreeMap::new();
+
+    let count = match read_len(reader, "Tags", MAX_TAGS) {
+        Ok(count) => count,
+        Err(Error::Truncated) => return Ok(tags),
+        Err(e) => return Err(e),
+    };
+
+    let mut size = 0u;
+    for _ in range(0, count) {
+        let key = read_str(reader, "Tag", MAX_TAG_LEN);
+        if let Err(e) = key {
+            return Err(e);
+        }
+
+        let value = read_str(reader, "Tag", MAX_TAG_LEN);
+        if let Err(e) = value {
+            return Err(e);
+        }
+
+        let (key, value) = (key.unwrap(), value.unwrap());
+        size += key.len() + value.len();
+        if size > MAX_TAGS_SIZE {
+            return Err(Error::TooLong {
+                field: "Tags",
+                len: size,
+                max: MAX_TAGS_SIZE,
+            });
+        }
+        tags.insert(key, value);
+    }
+    Ok(tags)
+}
+
+/// Returns the total length of the keys and values of `tags`, which is
+/// bounded by `MAX_TAGS_SIZE`.
+pub fn tags_size(tags: &BTreeMap<String, String>) -> uint {
+    tags.iter().fold(0, |size, (key, value)| size + key.len() + value.len())
+}
+
+/// A message which ends before its Lamport time comes from a node which
+/// predates Lamport clocks, and is at time 0.
+fn read_ltime<R: Input>(reader: &mut R) -> ErosionResult<u64> {
+    match reader.read_be_u64() {
+        Ok(ltime) => Ok(ltime),
+        Err(e) => match FromError::from_error(e) {
+            Error::Truncated => Ok(0),
+            e => Err(e),
+        },
+    }
+}
+
+/// The legacy layout only has room for IPv4 addresses, IPv6 addresses need
+/// the MessagePack wire format.
+fn write_addr<W: Writer>(writer: &mut W, addr: &SocketAddr) -> ErosionResult<()> {
+    match addr.ip {
+        Ipv4Addr(a, b, c, d) => {
+            if let Err(e) = writer.write(&[a, b, c, d]) {
+                return Err(FromError::from_error(e));
             }
         },
-        Err(e) => Err(e),
+        _ => return Err(Error::InvalidField("address")),
+    }
+
+    if let Err(e) = writer.write_be_u16(addr.port) {
+        return Err(FromError::from_error(e));
+    }
+
+    Ok(())
+}
+
+fn read_addr<R: Input>(reader: &mut R) -> ErosionResult<SocketAddr> {
+    let ip = reader.read_exact(4);
+    if let Err(e) = ip {
+        return Err(FromError::from_error(e));
+    }
+    let ip = ip.unwrap();
+
+    let port = reader.read_be_u16();
+    if let Err(e) = port {
+        return Err(FromError::from_error(e));
+    }
+
+    Ok(SocketAddr {
+        ip: Ipv4Addr(ip[0], ip[1], ip[2], ip[3]),
+        port: port.unwrap(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+    use std::io::net::ip::{
+        Ipv4Addr,
+        SocketAddr,
+    };
+    use std::rand::{
+        Rng,
+        SeedableRng,
+        XorShiftRng,
+    };
+
+    use error::Error;
+
+    use super::{
+        Message,
+        MessageType,
+        MAX_NAME_LEN,
+        MAX_TAGS_SIZE,
+        write_compound,
+    };
+
+    fn addr() -> SocketAddr {
+        SocketAddr {
+            ip: Ipv4Addr(10, 0, 0, 1),
+            port: 7201,
+        }
+    }
+
+    fn tags() -> BTreeMap<String, String> {
+        let mut tags = BTreeMap::new();
+        tags.insert("role".to_string(), "web".to_string());
+        tags.insert("dc".to_string(), "eu-west".to_string());
+        tags
+    }
+
+    /// Every message which has a legacy layout.
+    fn messages() -> Vec<Message> {
+        vec![
+            Message::Ping { seq: 1, name: "a".to_string() },
+            Message::IndirectPing { addr: addr(), seq: 2, name: "b".to_string() },
+            Message::Ack { seq: 3, payload: vec![1, 2, 3] },
+            Message::Ack { seq: 4, payload: Vec::new() },
+            Message::Nack { seq: 5 },
+            Message::Suspect { inc: 6, name: "c".to_string(), from: "d".to_string() },
+            Message::Alive { inc: 7, name: "e".to_string(), addr: addr(), tags: tags(),
+                             ltime: 8 },
+            Message::Alive { inc: 9, name: "f".to_string(), addr: addr(),
+                             tags: BTreeMap::new(), ltime: 0 },
+            Message::Dead { inc: 10, name: "g".to_string(), from: "h".to_string(),
+                            ltime: 11 },
+            Message::ConflictQuery { seq: 12, name: "i".to_string() },
+            Message::Join { seq: 13, inc: 14, name: "j".to_string(), addr: addr(),
+                            tags: tags(), ltime: 15 },
+            Message::Reject { seq: 16, reason: "no".to_string() },
+            Message::UserEvent { ltime: 17, name: "deploy".to_string(),
+                                 payload: vec![0, 255] },
+            Message::Compound { msgs: vec![
+                Message::Nack { seq: 18 },
+                Message::Ping { seq: 19, name: "k".to_string() },
+            ] },
+        ]
+    }
+
+    fn encode(msg: &Message) -> Vec<u8> {
+        let mut buf = Vec::new();
+        msg.write(&mut buf).unwrap();
+        buf
+    }
+
+    fn decode(buf: &[u8]) -> Result<Message, Error> {
+        let mut reader = buf;
+        Message::read(&mut reader)
+    }
+
+    /// Messages ending with fields newer nodes added decode without them.
+    fn has_optional_trailer(msg: &Message) -> bool {
+        match msg {
+            &Message::Alive { .. } | &Message::Dead { .. } | &Message::Join { .. } => true,
+            &Message::Compound { .. } => true,
+            _ => false,
+        }
+    }
+
+    #[test]
+    fn every_message_round_trips() {
+        for msg in messages().iter() {
+            let decoded = decode(encode(msg).as_slice()).unwrap();
+            assert_eq!(format!("{}", decoded), format!("{}", msg));
+        }
+    }
+
+    #[test]
+    fn truncated_messages_fail() {
+        for msg in messages().iter() {
+            let buf = encode(msg);
+            for len in range(0, buf.len()) {
+                let result = decode(buf[..len]);
+                if !has_optional_trailer(msg) {
+                    assert!(result.is_err(), "{} decoded from {} bytes", msg, len);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn unknown_type_fails() {
+        match decode(&[0x7f, 0, 0, 0, 0]) {
+            Err(Error::UnknownMessageType(0x7f)) => {},
+            result => panic!("Unexpected {}", result),
+        }
+    }
+
+    #[test]
+    fn overlong_name_fails_before_reading_it() {
+        let mut buf = vec![MessageType::Ping as u8, 0, 0, 0, 1];
+        // A varint length far beyond the limit, with no name behind it
+        buf.push_all(&[0xff, 0xff, 0xff, 0xff, 0x0f]);
+        match decode(buf.as_slice()) {
+            Err(Error::TooLong { max, .. }) => assert_eq!(max, MAX_NAME_LEN),
+            result => panic!("Unexpected {}", result),
+        }
+    }
+
+    #[test]
+    fn overlong_varint_fails() {
+        let mut buf = vec![MessageType::Ping as u8, 0, 0, 0, 1];
+        buf.push_all(&[0x80u8, ..11]);
+        assert!(decode(buf.as_slice()).is_err());
+    }
+
+    #[test]
+    fn oversized_tags_fail() {
+        let mut tags = BTreeMap::new();
+        tags.insert("a".to_string(), String::from_char(MAX_TAGS_SIZE / 2, 'x'));
+        tags.insert("b".to_string(), String::from_char(MAX_TAGS_SIZE / 2, 'x'));
+        let msg = Message::Alive { inc: 1, name: "a".to_string(), addr: addr(), tags: tags,
+                                   ltime: 1 };
+        match msg.write(&mut Vec::new()) {
+            Err(Error::TooLong { max, .. }) => assert_eq!(max, MAX_TAGS_SIZE),
+            result => panic!("Unexpected {}", result),
+        }
+    }
+
+    #[test]
+    fn nested_compound_fails() {
+        let inner = encode(&Message::Compound { msgs: vec![Message::Nack { seq: 1 }] });
+        let mut buf = Vec::new();
+        write_compound(&mut buf, &[inner]).unwrap();
+        match decode(buf.as_slice()) {
+            Err(Error::InvalidField(..)) => {},
+            result => panic!("Unexpected {}", result),
+        }
+    }
+
+    #[test]
+    fn compound_with_missing_parts_fails() {
+        let mut buf = encode(&Message::Compound { msgs: vec![Message::Nack { seq: 1 }] });
+        // Claim one part more than there is
+        buf[1] += 1;
+        assert!(decode(buf.as_slice()).is_err());
+    }
+
+    #[test]
+    fn garbage_never_panics() {
+        let mut rng: XorShiftRng = SeedableRng::from_seed([1, 2, 3, 4]);
+        for _ in range(0u, 10000) {
+            let len = rng.gen_range(0u, 64);
+            let mut buf: Vec<u8> = rng.gen_iter::<u8>().take(len).collect();
+            // Start most of them with a known type, to get past the first byte
+            if !buf.is_empty() && !rng.gen_weighted_bool(4) {
+                buf[0] = rng.gen_range(0u8, MessageType::UserEvent as u8 + 1);
+            }
+            let _ = decode(buf.as_slice());
+        }
+    }
+
+    #[test]
+    fn corrupted_messages_never_panic() {
+        let mut rng: XorShiftRng = SeedableRng::from_seed([5, 6, 7, 8]);
+        for msg in messages().iter() {
+            let buf = encode(msg);
+            for _ in range(0u, 500) {
+                let mut corrupted = buf.clone();
+                let index = rng.gen_range(0, corrupted.len());
+                corrupted[index] = rng.gen();
+                let _ = decode(corrupted.as_slice());
+            }
+        }
     }
 }
@@ -0,0 +1,318 @@
+use std::error::FromError;
+use std::io::Writer;
+
+use error::{
+    Error,
+    ErosionResult,
+};
+
+use input::{
+    read_bounded,
+    Input,
+};
+
+/// The longest string, binary or container we accept. Nothing longer fits
+/// into a UDP packet anyway, and it bounds what a hostile packet can make us
+/// allocate.
+const MAX_LEN: uint = 65535;
+
+/// How deeply arrays and maps may be nested.
+const MAX_DEPTH: uint = 8;
+
+/// A MessagePack value.
+#[deriving(Clone, PartialEq, Show)]
+pub enum Value {
+    Nil,
+    Bool(bool),
+    Int(i64),
+    UInt(u64),
+    F64(f64),
+    Str(String),
+    Bin(Vec<u8>),
+    Array(Vec<Value>),
+    Map(Vec<(Value, Value)>),
+}
+
+impl Value {
+    /// Look up `key` in a map with string keys.
+    pub fn get(&self, key: &str) -> Option<&Value> {
+        match self {
+            &Value::Map(ref entries) => {
+                for &(ref k, ref v) in entries.iter() {
+                    if let &Value::Str(ref k) = k {
+                        if k.as_slice() == key {
+                            return Some(v);
+                        }
+                    }
+                }
+                None
+            },
+            _ => None,
+        }
+    }
+
+    pub fn as_u64(&self) -> Option<u64> {
+        match self {
+            &Value::UInt(v) => Some(v),
+            &Value::Int(v) if v >= 0 => Some(v as u64),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            &Value::Str(ref v) => Some(v.as_slice()),
+            _ => None,
+        }
+    }
+
+    pub fn as_bin(&self) -> Option<&[u8]> {
+        match self {
+            &Value::Bin(ref v) => Some(v.as_slice()),
+            _ => None,
+        }
+    }
+
+    pub fn as_array(&self) -> Option<&[Value]> {
+        match self {
+            &Value::Array(ref v) => Some(v.as_slice()),
+            _ => None,
+        }
+    }
+}
+
+pub fn write_value<W: Writer>(writer: &mut W, value: &Value) -> ErosionResult<()> {
+    let result = match value {
+        &Value::Nil => writer.write_u8(0xc0),
+        &Value::Bool(false) => writer.write_u8(0xc2),
+        &Value::Bool(true) => writer.write_u8(0xc3),
+        &Value::UInt(v) => write_uint(writer, v),
+        &Value::Int(v) if v >= 0 => write_uint(writer, v as u64),
+        &Value::Int(v) => {
+            if v >= -32 {
+                writer.write_i8(v as i8)
+            } else {
+                writer.write_u8(0xd3).and_then(|()| writer.write_be_i64(v))
+            }
+        },
+        &Value::F64(v) => writer.write_u8(0xcb).and_then(|()| writer.write_be_f64(v)),
+        &Value::Str(ref v) => {
+            if let Err(e) = write_header(writer, 0xa0, 31, Some(0xd9), 0xda, 0xdb, v.len()) {
+                return Err(e);
+            }
+            writer.write_str(v.as_slice())
+        },
+        &Value::Bin(ref v) => {
+            if let Err(e) = write_header(writer, 0, 0, Some(0xc4), 0xc5, 0xc6, v.len()) {
+                return Err(e);
+            }
+            writer.write(v.as_slice())
+        },
+        &Value::Array(ref v) => {
+            if let Err(e) = write_header(writer, 0x90, 15, None, 0xdc, 0xdd, v.len()) {
+                return Err(e);
+            }
+            for item in v.iter() {
+                if let Err(e) = write_value(writer, item) {
+                    return Err(e);
+                }
+            }
+            Ok(())
+        },
+        &Value::Map(ref v) => {
+            if let Err(e) = write_header(writer, 0x80, 15, None, 0xde, 0xdf, v.len()) {
+                return Err(e);
+            }
+            for &(ref key, ref value) in v.iter() {
+                if let Err(e) = write_value(writer, key) {
+                    return Err(e);
+                }
+                if let Err(e) = write_value(writer, value) {
+                    return Err(e);
+                }
+            }
+            Ok(())
+        },
+    };
+
+    match result {
+        Ok(()) => Ok(()),
+        Err(e) => Err(FromError::from_error(e)),
+    }
+}
+
+pub fn read_value<R: Input>(reader: &mut R) -> ErosionResult<Value> {
+    read_nested(reader, 0)
+}
+
+fn write_uint<W: Writer>(writer: &mut W, v: u64) -> ::std::io::IoResult<()> {
+    if v < 0x80 {
+        writer.write_u8(v as u8)
+    } else if v <= 0xff {
+        writer.write_u8(0xcc).and_then(|()| writer.write_u8(v as u8))
+    } else if v <= 0xffff {
+        writer.write_u8(0xcd).and_then(|()| writer.write_be_u16(v as u16))
+    } else if v <= 0xffffffff {
+        writer.write_u8(0xce).and_then(|()| writer.write_be_u32(v as u32))
+    } else {
+        writer.write_u8(0xcf).and_then(|()| writer.write_be_u64(v))
+    }
+}
+
+/// Write the header of a string, binary, array or map of `len` elements.
+/// `fix` is the marker of the short form, which holds up to `fix_max`
+/// elements; `marker8` is missing for arrays and maps.
+fn write_header<W: Writer>(writer: &mut W, fix: u8, fix_max: uint, marker8: Option<u8>,
+                           marker16: u8, marker32: u8, len: uint) -> ErosionResult<()> {
+    if len > MAX_LEN {
+        return Err(Error::TooLong {
+            field: "MessagePack value",
+            len: len,
+            max: MAX_LEN,
+        });
+    }
+
+    let result = if fix_max > 0 && len <= fix_max {
+        writer.write_u8(fix | len as u8)
+    } else if marker8.is_some() && len <= 0xff {
+        writer.write_u8(marker8.unwrap()).and_then(|()| writer.write_u8(len as u8))
+    } else if len <= 0xffff {
+        writer.write_u8(marker16).and_then(|()| writer.write_be_u16(len as u16))
+    } else {
+        writer.write_u8(marker32).and_then(|()| writer.write_be_u32(len as u32))
+    };
+
+    match result {
+        Ok(()) => Ok(()),
+        Err(e) => Err(FromError::from_error(e)),
+    }
+}
+
+fn read_nested<R: Input>(reader: &mut R, depth: uint) -> ErosionResult<Value> {
+    let marker = reader.read_u8();
+    if let Err(e) = marker {
+        return Err(FromError::from_error(e));
+    }
+    let marker = marker.unwrap();
+
+    match marker {
+        0x00...0x7f => Ok(Value::UInt(marker as u64)),
+        0xe0...0xff => Ok(Value::Int(marker as i8 as i64)),
+        0x80...0x8f => read_map(reader, (marker & 0x0f) as uint, depth),
+        0x90...0x9f => read_array(reader, (marker & 0x0f) as uint, depth),
+        0xa0...0xbf => read_str(reader, (marker & 0x1f) as uint),
+        0xc0 => Ok(Value::Nil),
+        0xc2 => Ok(Value::Bool(false)),
+        0xc3 => Ok(Value::Bool(true)),
+        0xc4 => read_len8(reader).and_then(|len| read_bin(reader, len)),
+        0xc5 => read_len16(reader).and_then(|len| read_bin(reader, len)),
+        0xc6 => read_len32(reader).and_then(|len| read_bin(reader, len)),
+        0xcb => convert(reader.read_be_f64().map(|v| Value::F64(v))),
+        0xcc => convert(reader.read_u8().map(|v| Value::UInt(v as u64))),
+        0xcd => convert(reader.read_be_u16().map(|v| Value::UInt(v as u64))),
+        0xce => convert(reader.read_be_u32().map(|v| Value::UInt(v as u64))),
+        0xcf => convert(reader.read_be_u64().map(|v| Value::UInt(v))),
+        0xd0 => convert(reader.read_i8().map(|v| Value::Int(v as i64))),
+        0xd1 => convert(reader.read_be_i16().map(|v| Value::Int(v as i64))),
+        0xd2 => convert(reader.read_be_i32().map(|v| Value::Int(v as i64))),
+        0xd3 => convert(reader.read_be_i64().map(|v| Value::Int(v))),
+        0xd9 => read_len8(reader).and_then(|len| read_str(reader, len)),
+        0xda => read_len16(reader).and_then(|len| read_str(reader, len)),
+        0xdb => read_len32(reader).and_then(|len| read_str(reader, len)),
+        0xdc => read_len16(reader).and_then(|len| read_array(reader, len, depth)),
+        0xdd => read_len32(reader).and_then(|len| read_array(reader, len, depth)),
+        0xde => read_len16(reader).and_then(|len| read_map(reader, len, depth)),
+        0xdf => read_len32(reader).and_then(|len| read_map(reader, len, depth)),
+        // Extension types and float32 are not used
+        _ => Err(Error::InvalidField("MessagePack marker")),
+    }
+}
+
+fn convert(result: ::std::io::IoResult<Value>) -> ErosionResult<Value> {
+    match result {
+        Ok(value) => Ok(value),
+        Err(e) => Err(FromError::from_error(e)),
+    }
+}
+
+fn read_len8<R: Input>(reader: &mut R) -> ErosionResult<uint> {
+    match reader.read_u8() {
+        Ok(len) => Ok(len as uint),
+        Err(e) => Err(FromError::from_error(e)),
+    }
+}
+
+fn read_len16<R: Input>(reader: &mut R) -> ErosionResult<uint> {
+    match reader.read_be_u16() {
+        Ok(len) => Ok(len as uint),
+        Err(e) => Err(FromError::from_error(e)),
+    }
+}
+
+fn read_len32<R: Input>(reader: &mut R) -> ErosionResult<uint> {
+    match reader.read_be_u32() {
+        Ok(len) => check_len(len as uint),
+        Err(e) => Err(FromError::from_error(e)),
+    }
+}
+
+fn check_len(len: uint) -> ErosionResult<uint> {
+    if len > MAX_LEN {
+        return Err(Error::TooLong {
+            field: "MessagePack value",
+            len: len,
+            max: MAX_LEN,
+        });
+    }
+    Ok(len)
+}
+
+fn read_bin<R: Input>(reader: &mut R, len: uint) -> ErosionResult<Value> {
+    read_bounded(reader, len).map(|bytes| Value::Bin(bytes))
+}
+
+fn read_str<R: Input>(reader: &mut R, len: uint) -> ErosionResult<Value> {
+    match read_bounded(reader, len) {
+        Ok(bytes) => match String::from_utf8(bytes) {
+            Ok(s) => Ok(Value::Str(s)),
+            Err(_) => Err(Error::InvalidUtf8),
+        },
+        Err(e) => Err(e),
+    }
+}
+
+fn read_array<R: Input>(reader: &mut R, len: uint, depth: uint) -> ErosionResult<Value> {
+    if depth >= MAX_DEPTH {
+        return Err(Error::InvalidField("MessagePack nesting"));
+    }
+
+    // Grow as elements are actually read, rather than trusting `len`
+    let mut items = Vec::new();
+    for _ in range(0, len) {
+        match read_nested(reader, depth + 1) {
+            Ok(item) => items.push(item),
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(Value::Array(items))
+}
+
+fn read_map<R: Input>(reader: &mut R, len: uint, depth: uint) -> ErosionResult<Value> {
+    if depth >= MAX_DEPTH {
+        return Err(Error::InvalidField("MessagePack nesting"));
+    }
+
+    let mut entries = Vec::new();
+    for _ in range(0, len) {
+        let key = read_nested(reader, depth + 1);
+        if let Err(e) = key {
+            return Err(e);
+        }
+        let value = read_nested(reader, depth + 1);
+        if let Err(e) = value {
+            return Err(e);
+        }
+        entries.push((key.unwrap(), value.unwrap()));
+    }
+    Ok(Value::Map(entries))
+}
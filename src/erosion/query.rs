@@ -0,0 +1,219 @@
+use std::collections::{
+    HashMap,
+    HashSet,
+};
+use std::io::net::ip::SocketAddr;
+use std::sync::{
+    Arc,
+    Mutex,
+};
+use std::time::Duration;
+
+use time;
+use time::Timespec;
+
+use ack::{
+    AckHandler,
+    AckRouter,
+};
+
+use lamport::RecentEvents;
+
+/// What a member answered to a query.
+#[deriving(Clone, Show)]
+pub enum QueryEvent {
+    /// The member received the query and it passed its filter.
+    Ack(String),
+
+    /// The response of the member's query delegate.
+    Response {
+        from: String,
+        payload: Vec<u8>,
+    },
+}
+
+/// The answers to a query, as they arrive until its deadline.
+pub struct QueryResponses {
+    id: u32,
+    ltime: u64,
+    deadline: Timespec,
+    events: Receiver<QueryEvent>,
+}
+
+impl QueryResponses {
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+
+    /// Returns the Lamport time the query was sent at.
+    pub fn ltime(&self) -> u64 {
+        self.ltime
+    }
+
+    /// Returns the time after which answers are dropped.
+    pub fn deadline(&self) -> Timespec {
+        self.deadline
+    }
+
+    /// Block until the next answer arrives. Returns `None` once the
+    /// deadline passed.
+    pub fn recv(&self) -> Option<QueryEvent> {
+        self.events.recv_opt().ok()
+    }
+}
+
+/// Answers are sent to us directly and relayed, so each member's ack and
+/// response is only passed on once.
+struct PendingQuery {
+    events: Sender<QueryEvent>,
+    acks: HashSet<String>,
+    responses: HashSet<String>,
+}
+
+/// Ends a query at its deadline. Dropping the sender ends its responses.
+struct QueryDeadline {
+    id: u32,
+    pending: Arc<Mutex<HashMap<u32, PendingQuery>>>,
+}
+
+impl AckHandler for QueryDeadline {
+    /// No ack is expected for a query, a stray one ends it early.
+    fn ack(&mut self, _: Vec<u8>, _: Duration) {
+        self.timeout();
+    }
+
+    fn timeout(&mut self) {
+        self.pending.lock().remove(&self.id);
+    }
+}
+
+/// The queries we sent which are waiting for answers, and those we received
+/// recently.
+pub struct Queries {
+    pending: Arc<Mutex<HashMap<u32, PendingQuery>>>,
+
+    /// Queries are gossiped to us several times, but only answered once.
+    /// Each is kept with the address its answers go to.
+    recent: Mutex<RecentEvents<(String, u32, SocketAddr)>>,
+}
+
+impl Queries {
+    /// `buffer_len` is the number of Lamport times the received queries
+    /// are remembered for.
+    pub fn new(buffer_len: uint) -> Queries {
+        Queries {
+            pending: Arc::new(Mutex::new(HashMap::new())),
+            recent: Mutex::new(RecentEvents::new(buffer_len)),
+        }
+    }
+
+    /// Wait for the answers to the query `id`, sent at `ltime`, until
+    /// `timeout` is over. The deadline is kept by `acks`, so `id` must not
+    /// be used for a ping.
+    pub fn register(&self, id: u32, ltime: u64, timeout: Duration,
+                    acks: &AckRouter) -> QueryResponses {
+        let (tx, rx) = channel();
+        self.pending.lock().insert(id, PendingQuery {
+            events: tx,
+            acks: HashSet::new(),
+            responses: HashSet::new(),
+        });
+
+        acks.register(id, timeout, box QueryDeadline {
+            id: id,
+            pending: self.pending.clone(),
+        });
+
+        QueryResponses {
+            id: id,
+            ltime: ltime,
+            deadline: time::get_time() + timeout,
+            events: rx,
+        }
+    }
+
+    /// Pass on the answer of `from` to the query `id`, unless the query is
+    /// over or the answer is a duplicate.
+    pub fn answer(&self, id: u32, from: String, ack: bool, payload: Vec<u8>) {
+        let mut pending = self.pending.lock();
+        let query = match pending.get_mut(&id) {
+            Some(query) => query,
+            None => {
+                info!("Dropped answer from {} to expired query {}", from, id);
+                return;
+            },
+        };
+
+        let event = if ack {
+            if !query.acks.insert(from.clone()) {
+                return;
+            }
+            QueryEvent::Ack(from)
+        } else {
+            if !query.responses.insert(from.clone()) {
+                return;
+            }
+            QueryEvent::Response {
+                from: from,
+                payload: payload,
+            }
+        };
+        let _ = query.events.send_opt(event);
+    }
+
+    /// Remember the query `id` of `from`, sent at `ltime` and answered at
+    /// `addr`, when our query clock is at `now`. Returns `false` if it was
+    /// seen already, or is too old to tell.
+    pub fn witness(&self, from: &str, id: u32, addr: SocketAddr, ltime: u64,
+                   now: u64) -> bool {
+        self.recent.lock().witness(ltime, now, (from.to_string(), id, addr))
+    }
+
+    /// Whether we recently received and passed on a query `id` answered at
+    /// `addr`, so answers to it may be relayed there.
+    pub fn is_relayable(&self, id: u32, addr: SocketAddr) -> bool {
+        self.recent.lock().any(|&(_, recent_id, recent_addr)| {
+            recent_id == id && recent_addr == addr
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use ack::AckRouter;
+
+    use super::{
+        Queries,
+        QueryEvent,
+    };
+
+    #[test]
+    fn answers_are_passed_on_until_the_deadline() {
+        let acks = AckRouter::start();
+        let queries = Queries::new(8);
+        let responses = queries.register(1, 1, Duration::milliseconds(10), &acks);
+
+        queries.answer(1, "a".to_string(), true, Vec::new());
+        queries.answer(1, "a".to_string(), true, Vec::new());
+        match responses.recv() {
+            Some(QueryEvent::Ack(from)) => assert_eq!(from.as_slice(), "a"),
+            event => panic!("Unexpected {}", event),
+        }
+
+        // The duplicate was dropped, and the deadline ends the responses
+        assert!(responses.recv().is_none());
+        acks.stop();
+    }
+
+    #[test]
+    fn stopping_the_ack_router_ends_the_query() {
+        let acks = AckRouter::start();
+        let queries = Queries::new(8);
+        let responses = queries.register(1, 1, Duration::minutes(1), &acks);
+
+        acks.stop();
+        assert!(responses.recv().is_none());
+    }
+}
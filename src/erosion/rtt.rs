@@ -0,0 +1,154 @@
+use std::collections::{
+    HashMap,
+    RingBuf,
+};
+use std::num::Float;
+use std::sync::{
+    Mutex,
+    RWLock,
+};
+use std::time::Duration;
+
+/// The number of recent samples kept per member.
+const MEMBER_WINDOW: uint = 64;
+
+/// The number of recent samples kept for the whole cluster.
+const CLUSTER_WINDOW: uint = 256;
+
+/// The weight of a new sample in the moving average, the same as TCP uses
+/// for its smoothed RTT.
+const EWMA_WEIGHT: f64 = 0.125;
+
+/// Round-trip time statistics of a member.
+#[deriving(Clone, Show)]
+pub struct RttSummary {
+    /// Exponentially weighted moving average
+    pub ewma: Duration,
+
+    pub p50: Duration,
+    pub p90: Duration,
+    pub p99: Duration,
+
+    /// The number of samples the percentiles are computed from
+    pub samples: uint,
+}
+
+/// A moving average over every sample, and a window of the most recent
+/// samples for percentiles.
+struct RttStats {
+    window: uint,
+
+    /// In nanoseconds
+    ewma: f64,
+
+    /// In nanoseconds, oldest first
+    samples: RingBuf<i64>,
+}
+
+impl RttStats {
+    fn new(window: uint) -> RttStats {
+        RttStats {
+            window: window,
+            ewma: 0.0,
+            samples: RingBuf::with_capacity(window),
+        }
+    }
+
+    fn record(&mut self, rtt: Duration) {
+        let rtt = rtt.num_nanoseconds().unwrap_or(0);
+
+        if self.samples.is_empty() {
+            self.ewma = rtt as f64;
+        } else {
+            self.ewma += EWMA_WEIGHT * (rtt as f64 - self.ewma);
+        }
+
+        if self.samples.len() == self.window {
+            self.samples.pop_front();
+        }
+        self.samples.push_back(rtt);
+    }
+
+    /// Returns the `p` (within [0, 1]) percentile of the samples in the
+    /// window.
+    fn percentile(&self, p: f64) -> Option<Duration> {
+        if self.samples.is_empty() {
+            return None;
+        }
+
+        let mut sorted: Vec<i64> = self.samples.iter().map(|&rtt| rtt).collect();
+        sorted.sort();
+
+        let rank = (p * sorted.len() as f64).ceil() as uint;
+        let index = if rank == 0 { 0 } else { rank - 1 };
+        Some(Duration::nanoseconds(sorted[index]))
+    }
+
+    fn summary(&self) -> Option<RttSummary> {
+        if self.samples.is_empty() {
+            return None;
+        }
+
+        Some(RttSummary {
+            ewma: Duration::nanoseconds(self.ewma as i64),
+            p50: self.percentile(0.5).unwrap(),
+            p90: self.percentile(0.9).unwrap(),
+            p99: self.percentile(0.99).unwrap(),
+            samples: self.samples.len(),
+        })
+    }
+}
+
+/// Round-trip times measured by probing, per member and for the whole
+/// cluster.
+pub struct Latencies {
+    members: RWLock<HashMap<String, Mutex<RttStats>>>,
+
+    cluster: Mutex<RttStats>,
+}
+
+impl Latencies {
+    pub fn new() -> Latencies {
+        Latencies {
+            members: RWLock::new(HashMap::new()),
+            cluster: Mutex::new(RttStats::new(CLUSTER_WINDOW)),
+        }
+    }
+
+    pub fn record(&self, name: &str, rtt: Duration) {
+        self.cluster.lock().record(rtt);
+
+        {
+            let members = self.members.read();
+            if let Some(stats) = members.get(name) {
+                stats.lock().record(rtt);
+                return;
+            }
+        }
+
+        let mut stats = RttStats::new(MEMBER_WINDOW);
+        stats.record(rtt);
+        self.members.write().insert(name.to_string(), Mutex::new(stats));
+    }
+
+    pub fn summary(&self, name: &str) -> Option<RttSummary> {
+        match self.members.read().get(name) {
+            Some(stats) => stats.lock().summary(),
+            None => None,
+        }
+    }
+
+    pub fn remove(&self, name: &str) {
+        self.members.write().remove(name);
+    }
+
+    /// Returns the `p` percentile of the recent round-trip times to any
+    /// member, once the window is full.
+    pub fn cluster_percentile(&self, p: f64) -> Option<Duration> {
+        let cluster = self.cluster.lock();
+        if cluster.samples.len() < cluster.window {
+            return None;
+        }
+        cluster.percentile(p)
+    }
+}